@@ -1,12 +1,17 @@
-use super::{commands::AbilityId, *};
+use super::{
+    checksum::{StableHash, StableHasher},
+    commands::AbilityId,
+    fixed::{self, Fixed},
+    *,
+};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Minkle {
     pub drone_id: GameObjectId,
-    drone_target: Option<(f64, f64)>,
+    drone_target: Option<(Fixed, Fixed)>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Character {
     Minkle,
 }
@@ -20,7 +25,7 @@ impl Minkle {
                 .expect("Minkle created without object!");
             (pos.x, pos.y)
         };
-        let drone_id = game.create_game_object(x + 10.0, y);
+        let drone_id = game.create_game_object(x + Fixed::from_int(10), y);
         GravityAffected::new(game, minkle_id);
         game.characters.insert(minkle_id, Character::Minkle);
         game.minkles.insert(
@@ -38,11 +43,11 @@ impl Minkle {
                 if let Some(drone_pos) = game.positions.get_mut(&minkle.drone_id) {
                     let mut xtp = tx - drone_pos.x;
                     let mut ytp = ty - drone_pos.y;
-                    let mag_sq = xtp * xtp + ytp * ytp;
-                    let mag = mag_sq.sqrt();
+                    let mag = fixed::length(xtp, ytp);
                     if mag > DRONE_SPEED {
-                        xtp *= DRONE_SPEED / mag;
-                        ytp *= DRONE_SPEED / mag;
+                        let (nx, ny) = fixed::normalize(xtp, ytp);
+                        xtp = nx * DRONE_SPEED;
+                        ytp = ny * DRONE_SPEED;
                     } else {
                         minkle.drone_target = None;
                     }
@@ -54,14 +59,34 @@ impl Minkle {
     }
 }
 
-const DRONE_SPEED: f64 = 7.5;
+impl StableHash for Minkle {
+    fn stable_hash(&self, hasher: &mut StableHasher) {
+        self.drone_id.stable_hash(hasher);
+        match self.drone_target {
+            Some((tx, ty)) => {
+                hasher.write_u64(1);
+                tx.stable_hash(hasher);
+                ty.stable_hash(hasher);
+            }
+            None => hasher.write_u64(0),
+        }
+    }
+}
+
+const DRONE_SPEED: Fixed = Fixed::from_ratio(15, 2);
 impl Character {
+    pub fn has_ability(&self, ability_id: AbilityId) -> bool {
+        match self {
+            // Sending the drone somewhere
+            Character::Minkle => ability_id == AbilityId(0),
+        }
+    }
     pub fn apply_ability_command(
         game: &mut Game,
         id: GameObjectId,
         _ability_id: AbilityId,
-        tx: f64,
-        ty: f64,
+        tx: Fixed,
+        ty: Fixed,
     ) {
         match game.characters.get_mut(&id) {
             Some(Character::Minkle) => {
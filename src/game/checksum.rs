@@ -0,0 +1,328 @@
+use std::fmt::{self, Write};
+
+use crate::{PLAYER_FASTFALL_SPEED, PLAYER_FLOAT_SPEED, PLAYER_SPEED, TICK_TIME};
+
+use super::{
+    commands::{AbilityId, FrameChecksum, StateDump},
+    *,
+};
+
+/// 64-bit FNV-1a. Fully specified, so both peers get the same value for the same state no matter
+/// which platform or build they are running.
+pub struct StableHasher {
+    state: u64,
+}
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+impl StableHasher {
+    pub fn new() -> Self {
+        StableHasher {
+            state: FNV_OFFSET_BASIS,
+        }
+    }
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.state ^= *byte as u64;
+            self.state = self.state.wrapping_mul(FNV_PRIME);
+        }
+    }
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_bytes(&value.to_be_bytes());
+    }
+    pub fn finish(&self) -> u64 {
+        self.state
+    }
+}
+
+pub trait StableHash {
+    fn stable_hash(&self, hasher: &mut StableHasher);
+}
+impl StableHash for GameObjectId {
+    fn stable_hash(&self, hasher: &mut StableHasher) {
+        hasher.write_u64(self.0);
+    }
+}
+impl StableHash for Fixed {
+    fn stable_hash(&self, hasher: &mut StableHasher) {
+        hasher.write_u64(self.raw() as u64);
+    }
+}
+impl StableHash for Position {
+    fn stable_hash(&self, hasher: &mut StableHasher) {
+        self.x.stable_hash(hasher);
+        self.y.stable_hash(hasher);
+    }
+}
+impl StableHash for Player {
+    fn stable_hash(&self, hasher: &mut StableHasher) {
+        self.dx.stable_hash(hasher);
+        self.dy.stable_hash(hasher);
+        self.jump.stable_hash(hasher);
+    }
+}
+impl StableHash for Character {
+    fn stable_hash(&self, hasher: &mut StableHasher) {
+        match self {
+            Character::Minkle => hasher.write_u64(0),
+        }
+    }
+}
+
+/// Iterates a component map in id order, since `HashMap` order differs between processes.
+fn sorted_by_id<V>(map: &IdHashMap<V>) -> Vec<(&GameObjectId, &V)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by_key(|(id, _)| **id);
+    entries
+}
+fn hash_components<V: StableHash>(hasher: &mut StableHasher, map: &IdHashMap<V>) {
+    hasher.write_u64(map.len() as u64);
+    for (id, component) in sorted_by_id(map) {
+        id.stable_hash(hasher);
+        component.stable_hash(hasher);
+    }
+}
+fn dump_components<V: fmt::Debug>(out: &mut String, name: &str, map: &IdHashMap<V>) {
+    writeln!(out, "{}:", name).unwrap();
+    for (id, component) in sorted_by_id(map) {
+        writeln!(out, "  {:?}: {:?}", id, component).unwrap();
+    }
+}
+
+impl Game {
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = StableHasher::new();
+        hasher.write_u64(self.id_counter);
+        hash_components(&mut hasher, &self.positions);
+        hash_components(&mut hasher, &self.players);
+        hash_components(&mut hasher, &self.gravity_affected);
+        hash_components(&mut hasher, &self.characters);
+        hash_components(&mut hasher, &self.minkles);
+        hasher.finish()
+    }
+    /// Human readable state, ordered the same way as the checksum so two dumps can be diffed.
+    pub fn dump(&self) -> String {
+        let mut out = format!("id_counter: {}\n", self.id_counter);
+        dump_components(&mut out, "positions", &self.positions);
+        dump_components(&mut out, "players", &self.players);
+        dump_components(&mut out, "gravity_affected", &self.gravity_affected);
+        dump_components(&mut out, "characters", &self.characters);
+        dump_components(&mut out, "minkles", &self.minkles);
+        out
+    }
+}
+
+/// How many frames of the scripted game `simulation_hash` plays through.
+const SIMULATION_HASH_FRAMES: u64 = 60;
+/// Hash of a short scripted game and the tick rate, so builds that would play the same match
+/// differently, e.g. because a constant changed, can tell before they play together.
+pub fn simulation_hash() -> u64 {
+    let mut game = Game::new();
+    let player_id = Player::new(&mut game, Fixed::from_int(100), Fixed::from_int(100));
+    Minkle::new(&mut game, player_id);
+    for time in 0..SIMULATION_HASH_FRAMES {
+        let command = match time {
+            0 => Some(Command::MoveByCommand(PLAYER_SPEED, PLAYER_FLOAT_SPEED)),
+            10 => Some(Command::AbilityCommand(
+                AbilityId(0),
+                Fixed::from_int(300),
+                Fixed::from_int(200),
+            )),
+            30 => Some(Command::MoveByCommand(
+                -PLAYER_SPEED,
+                -PLAYER_FASTFALL_SPEED,
+            )),
+            _ => None,
+        };
+        if let Some(command) = command {
+            command.apply(&mut game, player_id);
+        }
+        game.step();
+    }
+    let mut hasher = StableHasher::new();
+    hasher.write_u64(TICK_TIME.as_micros() as u64);
+    hasher.write_u64(game.checksum());
+    hasher.finish()
+}
+
+#[derive(Clone, Debug)]
+pub struct DesyncDetected {
+    pub frame: u64,
+    pub local: u64,
+    pub remote: u64,
+    pub local_state: String,
+    pub remote_state: String,
+}
+impl fmt::Display for DesyncDetected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Desync detected at frame {}: local checksum {:016x}, remote checksum {:016x}",
+            self.frame, self.local, self.remote
+        )?;
+        writeln!(f, "Local state:\n{}", self.local_state)?;
+        write!(f, "Remote state:\n{}", self.remote_state)
+    }
+}
+
+/// How many frames of checksums we hold on to while waiting for the other side's.
+const CHECKSUM_HISTORY: u64 = 600;
+
+/// Compares checksums of confirmed frames with the ones the other peer computed.
+///
+/// When they disagree both sides send each other a dump of their state, and once the remote dump
+/// arrives a `DesyncDetected` is reported. Only the first mismatch is reported, as every frame
+/// after it will differ too.
+pub struct DesyncDetector {
+    /// Our confirmed frames that the other side hasn't sent a checksum for yet.
+    unverified: TimeMap<(u64, Game)>,
+    /// Checksums from the other side for frames we haven't confirmed yet.
+    remote_checksums: TimeMap<u64>,
+    mismatch: Option<(u64, u64, u64, String)>,
+    remote_state: Option<StateDump>,
+    detected: Vec<DesyncDetected>,
+    finished: bool,
+}
+impl DesyncDetector {
+    pub fn new() -> Self {
+        DesyncDetector {
+            unverified: new_time_map(),
+            remote_checksums: new_time_map(),
+            mismatch: None,
+            remote_state: None,
+            detected: Vec::new(),
+            finished: false,
+        }
+    }
+    /// Records a frame that has become final locally, returning its checksum to send to the other
+    /// side and, if it already disagrees with theirs, our state to send along with it.
+    pub fn add_local_frame(
+        &mut self,
+        frame: u64,
+        game: Game,
+    ) -> (FrameChecksum, Option<StateDump>) {
+        let checksum = game.checksum();
+        let frame_checksum = FrameChecksum { frame, checksum };
+        if self.mismatch.is_some() || self.finished {
+            return (frame_checksum, None);
+        }
+        self.forget_before(frame.saturating_sub(CHECKSUM_HISTORY));
+        let dump = match self.remote_checksums.remove(&frame) {
+            Some(remote) if remote != checksum => {
+                Some(self.record_mismatch(frame, checksum, remote, &game))
+            }
+            Some(_) => None,
+            None => {
+                self.unverified.insert(frame, (checksum, game));
+                None
+            }
+        };
+        (frame_checksum, dump)
+    }
+    /// Returns our state to send to the other side if their checksum disagrees with ours.
+    pub fn add_remote_checksum(&mut self, remote: FrameChecksum) -> Option<StateDump> {
+        if self.mismatch.is_some() || self.finished {
+            return None;
+        }
+        match self.unverified.remove(&remote.frame) {
+            Some((local, game)) if local != remote.checksum => {
+                Some(self.record_mismatch(remote.frame, local, remote.checksum, &game))
+            }
+            Some(_) => None,
+            None => {
+                self.remote_checksums.insert(remote.frame, remote.checksum);
+                None
+            }
+        }
+    }
+    pub fn add_remote_state(&mut self, dump: StateDump) {
+        if !self.finished {
+            self.remote_state = Some(dump);
+            self.check_detected();
+        }
+    }
+    pub fn take_detected(&mut self) -> Vec<DesyncDetected> {
+        std::mem::take(&mut self.detected)
+    }
+    fn record_mismatch(&mut self, frame: u64, local: u64, remote: u64, game: &Game) -> StateDump {
+        let state = game.dump();
+        self.mismatch = Some((frame, local, remote, state.clone()));
+        self.unverified.clear();
+        self.remote_checksums.clear();
+        self.check_detected();
+        StateDump { frame, state }
+    }
+    fn check_detected(&mut self) {
+        let matches_frame = match (&self.mismatch, &self.remote_state) {
+            (Some((frame, ..)), Some(dump)) => *frame == dump.frame,
+            _ => false,
+        };
+        if matches_frame {
+            let (frame, local, remote, local_state) = self.mismatch.take().unwrap();
+            let remote_state = self.remote_state.take().unwrap().state;
+            self.detected.push(DesyncDetected {
+                frame,
+                local,
+                remote,
+                local_state,
+                remote_state,
+            });
+            self.finished = true;
+        }
+    }
+    fn forget_before(&mut self, frame: u64) {
+        self.unverified.retain(|time, _| *time >= frame);
+        self.remote_checksums.retain(|time, _| *time >= frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Has the other side disagree about frames 3 and 4 after agreeing about frame 2, hearing
+    /// their checksums before ours are ready if `remote_first`, and returns what was detected.
+    fn detect_desync(remote_first: bool) -> (Game, Vec<DesyncDetected>) {
+        let mut game = Game::new();
+        Player::new(&mut game, Fixed::from_int(100), Fixed::from_int(100));
+        let checksum = game.checksum();
+        let mut detector = DesyncDetector::new();
+        let mut dumps = Vec::new();
+        for frame in 2..5 {
+            let remote = FrameChecksum {
+                frame,
+                checksum: if frame == 2 { checksum } else { checksum ^ 1 },
+            };
+            if remote_first {
+                dumps.extend(detector.add_remote_checksum(remote));
+                dumps.extend(detector.add_local_frame(frame, game.clone()).1);
+            } else {
+                dumps.extend(detector.add_local_frame(frame, game.clone()).1);
+                dumps.extend(detector.add_remote_checksum(remote));
+            }
+        }
+        assert!(matches!(dumps[..], [StateDump { frame: 3, .. }]));
+        assert_eq!(dumps[0].state, game.dump());
+        assert!(detector.take_detected().is_empty());
+        for frame in 3..5 {
+            detector.add_remote_state(StateDump {
+                frame,
+                state: format!("remote state at {}", frame),
+            });
+        }
+        let detected = detector.take_detected();
+        (game, detected)
+    }
+
+    #[test]
+    fn reports_the_first_mismatch_in_either_order() {
+        for remote_first in [false, true] {
+            let (game, detected) = detect_desync(remote_first);
+            assert_eq!(detected.len(), 1);
+            assert_eq!(detected[0].frame, 3);
+            assert_eq!(detected[0].local, game.checksum());
+            assert_eq!(detected[0].remote, game.checksum() ^ 1);
+            assert_eq!(detected[0].local_state, game.dump());
+            assert_eq!(detected[0].remote_state, "remote state at 3");
+        }
+    }
+}
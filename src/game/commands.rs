@@ -1,12 +1,96 @@
-use crate::PLAYER_JUMP_SPEED;
+use std::fmt;
 
-use super::{*, gravity::FLOOR_HEIGHT};
+use crate::{
+    PLAYER_FASTFALL_SPEED, PLAYER_FLOAT_SPEED, PLAYER_JUMP_SPEED, PLAYER_SPEED, WINDOW_HEIGHT,
+    WINDOW_WIDTH,
+};
+
+use super::{fixed::Fixed, gravity::FLOOR_HEIGHT, *};
 use alkahest::alkahest;
 
+/// The first thing each side of a new connection sends.
 #[derive(Clone, Debug)]
 #[alkahest(Formula, SerializeRef, Deserialize)]
 pub struct Handshake {
+    /// Comes first so it's read before anything whose layout it might change.
+    pub protocol_version: u32,
+    /// From `simulation_hash`, so builds that would simulate differently never play together.
+    pub simulation_hash: u64,
     pub my_name: String,
+    /// Features the sender needs the other side to support.
+    pub requested_features: Vec<String>,
+}
+/// Why one side of a connection refused to go on with it.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub enum Rejection {
+    /// The protocol version the rejecting side speaks.
+    ProtocolVersion(u32),
+    /// The rejecting side's simulation hash.
+    SimulationHash(u64),
+    UnsupportedFeature(String),
+    /// The match has started and every slot is taken, so only players rejoining it and spectators
+    /// can connect.
+    MatchFull,
+    /// Nobody with that slot and rejoin token is playing.
+    UnknownRejoin,
+}
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::ProtocolVersion(version) => {
+                write!(f, "only protocol version {} is supported", version)
+            }
+            Rejection::SimulationHash(hash) => write!(
+                f,
+                "the game would be simulated differently (simulation hash {:016x})",
+                hash
+            ),
+            Rejection::UnsupportedFeature(feature) => {
+                write!(f, "the {} feature isn't supported", feature)
+            }
+            Rejection::MatchFull => {
+                write!(f, "the match has already started and every slot is taken")
+            }
+            Rejection::UnknownRejoin => write!(f, "nobody with that rejoin token is playing"),
+        }
+    }
+}
+/// Sent by each side once it's read the other's handshake.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub enum HandshakeReply {
+    Accepted,
+    Rejected(Rejection),
+}
+/// Index of a player in the session, assigned by the host, which is always slot 0.
+pub type PlayerSlot = u32;
+/// Sent by the host to each client once every player has connected, or to a player joining a
+/// match already underway once they have a slot.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct SlotAssignment {
+    pub slot: PlayerSlot,
+    /// Names of all players, indexed by slot. Empty for open slots.
+    pub player_names: Vec<String>,
+    /// Proves who's rejoining after losing the connection, as only the host and the client in
+    /// this slot know it. Always 0 for the host itself.
+    pub rejoin_token: u64,
+    /// Slots nobody has joined yet. Their players stand still, and nobody waits for their commands
+    /// until the host brings whoever joins in them up to date.
+    pub open_slots: Vec<PlayerSlot>,
+    /// Set when joining a match that has already started, which can't be played until the host
+    /// brings us up to date the same as a player rejoining.
+    pub match_underway: bool,
+}
+/// Sent by a client straight after the handshake when reconnecting to a match, and answered with
+/// a `HandshakeReply`.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct RejoinRequest {
+    pub slot: PlayerSlot,
+    /// From our `SlotAssignment`.
+    pub rejoin_token: u64,
 }
 #[derive(Clone, Debug)]
 #[alkahest(Formula, SerializeRef, Deserialize)]
@@ -18,14 +102,41 @@ pub struct TimingPacket {
 pub struct SetInputDelay {
     pub input_delay: u64,
 }
-#[derive(Clone, Debug, Copy)]
+/// Sent by the host to each client last of all, so every player's frame 0 happens at the same time.
+#[derive(Clone, Debug)]
 #[alkahest(Formula, SerializeRef, Deserialize)]
-pub struct AbilityId(pub u8);
+pub struct StartCountdown {
+    /// How long after this arrives frame 0 is.
+    pub micros: u64,
+}
+/// Sent by the host to change every player's input delay for commands issued from `frame` on.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct ChangeInputDelay {
+    pub frame: u64,
+    pub input_delay: u64,
+}
+/// Echoed straight back by the client's network threads, so the host can keep measuring round
+/// trip times during a match.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct Ping {
+    /// Microseconds since the host's network threads started, so only meaningful to the host.
+    pub sent_at: u64,
+}
 #[derive(Clone, Debug)]
 #[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct RoundTrip {
+    pub micros: u64,
+}
+#[derive(Clone, Debug, Copy, PartialEq)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct AbilityId(pub u8);
+#[derive(Clone, Debug, PartialEq)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
 pub enum Command {
-    MoveByCommand(f64, f64),
-    AbilityCommand(AbilityId, f64, f64),
+    MoveByCommand(Fixed, Fixed),
+    AbilityCommand(AbilityId, Fixed, Fixed),
 }
 
 #[derive(Clone, Debug)]
@@ -34,21 +145,335 @@ pub struct TimedCommand {
     pub time: u64,
     pub command: Command,
 }
+/// Acknowledges that every command the sender will issue for frames before `frame` has already
+/// been sent.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct FrameAck {
+    pub frame: u64,
+    /// The frame the sender was on, so peers can tell how far apart their clocks are.
+    pub current_frame: u64,
+}
+/// How many frames ahead of a player the sender is, going by the latest frame it's heard of from
+/// them. Negative when behind.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct SlotAdvantage {
+    pub slot: PlayerSlot,
+    pub frames: i64,
+}
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct FrameAdvantage {
+    pub advantages: Vec<SlotAdvantage>,
+}
+
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct FrameChecksum {
+    pub frame: u64,
+    pub checksum: u64,
+}
+/// Sent after a checksum mismatch so both sides can see what the other one simulated.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct StateDump {
+    pub frame: u64,
+    pub state: String,
+}
+
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub enum PeerMessage {
+    Command(TimedCommand),
+    Ack(FrameAck),
+    Checksum(FrameChecksum),
+    StateDump(StateDump),
+    /// The sender's connection to the host went away. Whatever of theirs was relayed before this
+    /// is all there will be until they rejoin. A client's session also sends it to its own network
+    /// threads to have them drop the connection and reconnect.
+    Disconnected,
+    /// Like `Disconnected`, but the connection went quiet for too long rather than failing.
+    TimedOut,
+    /// Sent by the network threads whenever there's been nothing else to send for a while, so the
+    /// other side can tell a quiet connection from a dead one. Never reaches a session.
+    Heartbeat,
+    /// Sent and answered by the network threads, like `Heartbeat`.
+    Ping(Ping),
+    Pong(Ping),
+    /// A round trip time to the sender that the host's network threads measured. Only ever passed
+    /// to the host's own session.
+    RoundTrip(RoundTrip),
+    ChangeInputDelay(ChangeInputDelay),
+    /// Clients only keep time with the host, so theirs is only of interest to the host.
+    FrameAdvantage(FrameAdvantage),
+    /// The sender has connected to the host again and needs to be brought up to date. Only ever
+    /// passed from the host's network threads to its own session.
+    Reconnected,
+    /// Brings a player rejoining or joining late up to date, and tells everyone else to expect
+    /// their commands.
+    Rejoin(RejoinState),
+    Chat(Chat),
+    /// The host threw away one of a player's commands, which only that player needs to hear so
+    /// they can stop simulating it too.
+    CommandRejected(RejectedCommand),
+    /// Something our own network threads want reported, as they have no way to report it
+    /// themselves. Never sent over the network.
+    Notice(ConnectionNotice),
+}
+/// A command the host wouldn't schedule or relay, and why.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct RejectedCommand {
+    /// Who sent it.
+    pub slot: PlayerSlot,
+    pub command: TimedCommand,
+    pub reason: String,
+}
+/// Something a player typed for everyone else to read.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct Chat {
+    pub text: String,
+}
+/// Something that happened to a connection that the session only hears about from the network
+/// threads.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub enum ConnectionNotice {
+    /// Someone by this name started watching the match.
+    SpectatorJoined(String),
+    /// A connection made once the match had started was turned away, for this reason.
+    JoinRefused(String),
+    /// An attempt to reconnect to the host failed, for this reason. Another follows shortly.
+    ReconnectFailed(String),
+}
+/// A command along with who issued it and the frame it's for.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct ScheduledCommand {
+    pub time: u64,
+    pub slot: PlayerSlot,
+    pub command: Command,
+}
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct RejoinState {
+    pub slot: PlayerSlot,
+    /// The frame `state` is for.
+    pub frame: u64,
+    /// From `Game::save_state`.
+    pub state: Vec<u8>,
+    /// Every command the host has for `frame` and later, in the order the host applies them.
+    pub commands: Vec<ScheduledCommand>,
+    /// The host's current frame, which the rejoining player should simulate up to.
+    pub current_time: u64,
+    /// The rejoining player mustn't schedule any commands before this frame, as the others may
+    /// have already confirmed earlier ones.
+    pub first_command_frame: u64,
+    /// Players other than the rejoining one that are still connected.
+    pub connected: Vec<PlayerSlot>,
+    /// The input delay to use from now on, including any change still to take effect.
+    pub input_delay: u64,
+}
+/// A command tagged with the slot of the player who issued it.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct SlotCommand {
+    pub slot: PlayerSlot,
+    pub command: Command,
+}
+/// Every command for one frame, streamed to spectators once no more can arrive for it.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct ConfirmedFrame {
+    pub frame: u64,
+    pub commands: Vec<SlotCommand>,
+}
+/// Sent to a spectator before any confirmed frames, so it can pick up the match from a recent
+/// frame rather than replaying it from the start.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct SpectatorStart {
+    pub player_names: Vec<String>,
+    /// The frame `state` is for. Confirmed frames follow from this one on.
+    pub frame: u64,
+    /// From `Game::save_state`.
+    pub state: Vec<u8>,
+}
+/// A message from one player, relayed to the others by the host. Players only ever send the
+/// `message` part to the host, which knows who it came from.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct RoutedMessage {
+    pub from: PlayerSlot,
+    pub message: PeerMessage,
+}
+/// Everything ever sent over a connection is one of these, so any message can follow any other
+/// without the two ends having to agree on what comes next.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub enum Message {
+    /// Has to stay first, so even builds that disagree about everything else can tell each other
+    /// their protocol versions.
+    Handshake(Handshake),
+    HandshakeReply(HandshakeReply),
+    Timing(TimingPacket),
+    SlotAssignment(SlotAssignment),
+    SetInputDelay(SetInputDelay),
+    StartCountdown(StartCountdown),
+    SpectatorStart(SpectatorStart),
+    ConfirmedFrame(ConfirmedFrame),
+    /// From a player to the host.
+    Peer(PeerMessage),
+    /// From the host to a player.
+    Routed(RoutedMessage),
+    RejoinRequest(RejoinRequest),
+}
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Handshake(_) => "handshake",
+            Message::HandshakeReply(_) => "handshake reply",
+            Message::Timing(_) => "timing",
+            Message::SlotAssignment(_) => "slot assignment",
+            Message::SetInputDelay(_) => "input delay",
+            Message::StartCountdown(_) => "start countdown",
+            Message::SpectatorStart(_) => "spectator start",
+            Message::ConfirmedFrame(_) => "confirmed frame",
+            Message::Peer(_) | Message::Routed(_) => "match",
+            Message::RejoinRequest(_) => "rejoin request",
+        }
+    }
+}
+/// Lets each kind of message be sent and received as itself, wrapped in a `Message` on the wire.
+macro_rules! impl_message_conversions {
+    ($($variant:ident($item:ty)),* $(,)?) => {
+        $(
+            impl From<$item> for Message {
+                fn from(item: $item) -> Self {
+                    Message::$variant(item)
+                }
+            }
+            impl TryFrom<Message> for $item {
+                type Error = Message;
+                fn try_from(message: Message) -> Result<Self, Message> {
+                    match message {
+                        Message::$variant(item) => Ok(item),
+                        other => Err(other),
+                    }
+                }
+            }
+        )*
+    };
+}
+impl_message_conversions!(
+    Handshake(Handshake),
+    HandshakeReply(HandshakeReply),
+    Timing(TimingPacket),
+    SlotAssignment(SlotAssignment),
+    SetInputDelay(SetInputDelay),
+    StartCountdown(StartCountdown),
+    SpectatorStart(SpectatorStart),
+    ConfirmedFrame(ConfirmedFrame),
+    Peer(PeerMessage),
+    Routed(RoutedMessage),
+    RejoinRequest(RejoinRequest),
+);
+impl PeerMessage {
+    /// Whether the host passes this on to the other players. Messages only the host needs, or
+    /// that only come from its own network threads, aren't.
+    pub fn is_relayed(&self) -> bool {
+        !matches!(
+            self,
+            PeerMessage::Heartbeat
+                | PeerMessage::Ping(_)
+                | PeerMessage::Pong(_)
+                | PeerMessage::RoundTrip(_)
+                | PeerMessage::FrameAdvantage(_)
+                | PeerMessage::Reconnected
+                | PeerMessage::Notice(_)
+        )
+    }
+}
+/// Why a command from another player was thrown away instead of being scheduled.
+#[derive(Debug)]
+pub enum InvalidCommand {
+    /// Faster than any combination of movement keys moves a player.
+    MoveOutOfRange { dx: Fixed, dy: Fixed },
+    /// An ability the player's character doesn't have, or any ability from a player without a
+    /// character.
+    UnknownAbility(AbilityId),
+    /// An ability aimed outside the playfield, where no click could land.
+    TargetOutOfBounds { tx: Fixed, ty: Fixed },
+    /// For a frame further ahead than `limit`, which no player keeping time with us could have
+    /// reached yet.
+    TooFarAhead { time: u64, limit: u64 },
+    /// For a frame that can no longer be changed.
+    TooLate(RollbackError),
+}
+impl fmt::Display for InvalidCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvalidCommand::MoveOutOfRange { dx, dy } => {
+                write!(f, "moving by ({}, {}) is faster than allowed", dx, dy)
+            }
+            InvalidCommand::UnknownAbility(ability_id) => {
+                write!(f, "ability {} isn't one their character has", ability_id.0)
+            }
+            InvalidCommand::TargetOutOfBounds { tx, ty } => {
+                write!(f, "target ({}, {}) is outside the playfield", tx, ty)
+            }
+            InvalidCommand::TooFarAhead { time, limit } => write!(
+                f,
+                "command for frame {} is further ahead than frame {}",
+                time, limit
+            ),
+            InvalidCommand::TooLate(rollback_error) => write!(f, "{}", rollback_error),
+        }
+    }
+}
+impl std::error::Error for InvalidCommand {}
 impl Command {
+    /// Checks that `player_id` could have issued this command playing fairly, judging by what it
+    /// does rather than when it's for. Issuers check their own commands with this, and the host
+    /// checks everyone else's.
+    pub fn validate(&self, game: &Game, player_id: GameObjectId) -> Result<(), InvalidCommand> {
+        match self {
+            Command::MoveByCommand(dx, dy) => {
+                if (-PLAYER_SPEED..=PLAYER_SPEED).contains(dx)
+                    && (-PLAYER_FASTFALL_SPEED..=PLAYER_FLOAT_SPEED).contains(dy)
+                {
+                    Ok(())
+                } else {
+                    Err(InvalidCommand::MoveOutOfRange { dx: *dx, dy: *dy })
+                }
+            }
+            Command::AbilityCommand(ability_id, tx, ty) => {
+                if !(Fixed::ZERO..=Fixed::from_int(WINDOW_WIDTH as i64)).contains(tx)
+                    || !(Fixed::ZERO..=Fixed::from_int(WINDOW_HEIGHT as i64)).contains(ty)
+                {
+                    return Err(InvalidCommand::TargetOutOfBounds { tx: *tx, ty: *ty });
+                }
+                match game.characters.get(&player_id) {
+                    Some(character) if character.has_ability(*ability_id) => Ok(()),
+                    _ => Err(InvalidCommand::UnknownAbility(*ability_id)),
+                }
+            }
+        }
+    }
     pub fn apply(&self, game: &mut Game, player_id: GameObjectId) {
         match self {
             Command::MoveByCommand(dx, dy) => {
                 let player = game.players.get_mut(&player_id).unwrap();
                 let pos = game.positions.get(&player_id).unwrap();
                 let jump = if pos.y <= FLOOR_HEIGHT {
-                    if *dy > 0.0 {
+                    if *dy > Fixed::ZERO {
                         PLAYER_JUMP_SPEED
+                    } else {
+                        Fixed::ZERO
                     }
-                    else {
-                        0.0
-                    }
-                }
-                else {
+                } else {
                     player.jump
                 };
                 player.dx = *dx;
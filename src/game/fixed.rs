@@ -0,0 +1,124 @@
+use std::{
+    fmt,
+    ops::{Add, AddAssign, Div, Mul, Neg, Sub},
+};
+
+use alkahest::alkahest;
+use serde::{Deserialize, Serialize};
+
+const FRACTIONAL_BITS: u32 = 16;
+
+/// Signed fixed point number with 16 fractional bits.
+///
+/// All simulation maths goes through this instead of `f64` so that every peer computes
+/// bit-identical results regardless of compiler or CPU. Arithmetic wraps on overflow rather than
+/// panicking, so debug and release builds agree too.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    pub const ZERO: Fixed = Fixed(0);
+
+    pub const fn from_int(value: i64) -> Self {
+        Fixed(value << FRACTIONAL_BITS)
+    }
+    /// `numerator / denominator`, e.g. `from_ratio(15, 2)` for 7.5.
+    pub const fn from_ratio(numerator: i64, denominator: i64) -> Self {
+        Fixed((numerator << FRACTIONAL_BITS) / denominator)
+    }
+    pub const fn raw(self) -> i64 {
+        self.0
+    }
+    /// Rounds towards negative infinity.
+    pub const fn to_int(self) -> i64 {
+        self.0 >> FRACTIONAL_BITS
+    }
+    /// Only for display, never feed the result back into the simulation.
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / (1u64 << FRACTIONAL_BITS) as f64
+    }
+    /// Square root rounded down, or zero for negative numbers.
+    pub fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            return Fixed::ZERO;
+        }
+        // sqrt(raw / 2^16) * 2^16 == sqrt(raw * 2^16)
+        Fixed(integer_sqrt((self.0 as u128) << FRACTIONAL_BITS) as i64)
+    }
+}
+
+/// Largest `r` such that `r * r <= value`, using only integer operations.
+fn integer_sqrt(value: u128) -> u128 {
+    if value < 2 {
+        return value;
+    }
+    let mut estimate = 1u128 << ((128 - value.leading_zeros()) / 2 + 1);
+    loop {
+        let next = (estimate + value / estimate) / 2;
+        if next >= estimate {
+            return estimate;
+        }
+        estimate = next;
+    }
+}
+
+pub fn length(x: Fixed, y: Fixed) -> Fixed {
+    (x * x + y * y).sqrt()
+}
+/// Scales `(x, y)` to unit length, leaving a zero vector as it is.
+pub fn normalize(x: Fixed, y: Fixed) -> (Fixed, Fixed) {
+    let length = length(x, y);
+    if length == Fixed::ZERO {
+        (x, y)
+    } else {
+        (x / length, y / length)
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_add(other.0))
+    }
+}
+impl Sub for Fixed {
+    type Output = Fixed;
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0.wrapping_sub(other.0))
+    }
+}
+impl Mul for Fixed {
+    type Output = Fixed;
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed(((self.0 as i128 * other.0 as i128) >> FRACTIONAL_BITS) as i64)
+    }
+}
+impl Div for Fixed {
+    type Output = Fixed;
+    fn div(self, other: Fixed) -> Fixed {
+        Fixed((((self.0 as i128) << FRACTIONAL_BITS) / other.0 as i128) as i64)
+    }
+}
+impl Neg for Fixed {
+    type Output = Fixed;
+    fn neg(self) -> Fixed {
+        Fixed(self.0.wrapping_neg())
+    }
+}
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, other: Fixed) {
+        *self = *self + other;
+    }
+}
+
+impl fmt::Debug for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_f64())
+    }
+}
+impl fmt::Display for Fixed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_f64())
+    }
+}
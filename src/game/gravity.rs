@@ -1,19 +1,23 @@
-use super::*;
+use super::{
+    checksum::{StableHash, StableHasher},
+    fixed::Fixed,
+    *,
+};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GravityAffected {
-    current_velocity: f64,
+    current_velocity: Fixed,
 }
 
-pub const GRAVITY_ACCELERATION: f64 = 2.0;
-pub const FLOOR_HEIGHT: f64 = 20.0;
+pub const GRAVITY_ACCELERATION: Fixed = Fixed::from_int(2);
+pub const FLOOR_HEIGHT: Fixed = Fixed::from_int(20);
 impl GravityAffected {
     pub fn step(game: &mut Game) {
         let mut to_delete = Vec::new();
         for (id, gravity_affected) in game.gravity_affected.iter_mut() {
             if let Some(pos) = game.positions.get_mut(id) {
                 if pos.y <= FLOOR_HEIGHT {
-                    gravity_affected.current_velocity = 0.0;
+                    gravity_affected.current_velocity = Fixed::ZERO;
                 } else {
                     pos.y = FLOOR_HEIGHT.max(pos.y - gravity_affected.current_velocity);
                     gravity_affected.current_velocity += GRAVITY_ACCELERATION;
@@ -28,8 +32,13 @@ impl GravityAffected {
     }
     pub fn new(game: &mut Game, id: GameObjectId) {
         let gravity_affected = GravityAffected {
-            current_velocity: 0.0,
+            current_velocity: Fixed::ZERO,
         };
         game.gravity_affected.insert(id, gravity_affected);
     }
 }
+impl StableHash for GravityAffected {
+    fn stable_hash(&self, hasher: &mut StableHasher) {
+        self.current_velocity.stable_hash(hasher);
+    }
+}
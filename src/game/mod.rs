@@ -1,34 +1,45 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::hash::Hash;
 
 pub mod characters;
+pub mod checksum;
 pub mod commands;
+pub mod fixed;
 pub mod gravity;
+pub mod prediction;
+pub mod snapshot;
+use alkahest::alkahest;
 use sdl2::{
     rect::Rect,
     render::{Canvas, RenderTarget},
 };
+use serde::{Deserialize, Serialize};
 
 use crate::WINDOW_HEIGHT;
 
-use self::{characters::Character, characters::Minkle, commands::Command, gravity::GravityAffected};
+use self::{
+    characters::Character, characters::Minkle, commands::Command, fixed::Fixed,
+    gravity::GravityAffected, prediction::InputPredictor,
+};
 
-#[derive(Hash, Eq, Ord, PartialEq, PartialOrd, Debug, Copy, Clone)]
+#[derive(Hash, Eq, Ord, PartialEq, PartialOrd, Debug, Copy, Clone, Serialize, Deserialize)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
 pub struct GameObjectId(u64);
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Position {
-    pub x: f64,
-    pub y: f64,
+    pub x: Fixed,
+    pub y: Fixed,
 }
 
 impl Position {
-    pub fn get_distance_squared(&self, other: &Self) -> f64 {
+    pub fn get_distance_squared(&self, other: &Self) -> Fixed {
         let dx = self.x - other.x;
         let dy = self.y - other.y;
         dx * dx + dy * dy
     }
-    pub fn is_closer_than(&self, other: &Self, distance: f64) -> bool {
+    pub fn is_closer_than(&self, other: &Self, distance: Fixed) -> bool {
         let distance_sq = self.get_distance_squared(other);
         distance_sq < distance * distance
     }
@@ -68,17 +79,24 @@ impl std::hash::BuildHasher for U64DoNothingBuildHasher {
 }
 type IdHashMap<V> = HashMap<GameObjectId, V, U64DoNothingBuildHasher>;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Player {
-    pub dx: f64,
-    pub dy: f64,
-    pub jump: f64,
+    pub dx: Fixed,
+    pub dy: Fixed,
+    pub jump: Fixed,
 }
 
 impl Player {
-    pub fn new(game: &mut Game, x: f64, y: f64) -> GameObjectId {
+    pub fn new(game: &mut Game, x: Fixed, y: Fixed) -> GameObjectId {
         let id = game.create_game_object(x, y);
-        game.players.insert(id, Player { dx: 0.0, dy: 0.0, jump: 0.0});
+        game.players.insert(
+            id,
+            Player {
+                dx: Fixed::ZERO,
+                dy: Fixed::ZERO,
+                jump: Fixed::ZERO,
+            },
+        );
         id
     }
     pub fn step(game: &mut Game) {
@@ -111,7 +129,10 @@ fn new_id_hashmap<T>() -> IdHashMap<T> {
 
 const PLAYER_VISUAL_WIDTH: i32 = 6;
 pub fn convert_coords_from_sdl_coords(x: i32, y: i32) -> Position {
-    Position { x: x as f64, y: WINDOW_HEIGHT as f64 - y as f64}
+    Position {
+        x: Fixed::from_int(x as i64),
+        y: Fixed::from_int(WINDOW_HEIGHT as i64 - y as i64),
+    }
 }
 fn convert_rect_to_sdl_coords(mut rect: Rect) -> Rect {
     rect.y = WINDOW_HEIGHT as i32 - rect.y;
@@ -128,7 +149,7 @@ impl Game {
             minkles: new_id_hashmap(),
         }
     }
-    pub fn create_game_object(&mut self, x: f64, y: f64) -> GameObjectId {
+    pub fn create_game_object(&mut self, x: Fixed, y: Fixed) -> GameObjectId {
         let id = GameObjectId(self.id_counter);
         self.id_counter += 1;
         self.positions.insert(id, Position { x, y });
@@ -146,8 +167,8 @@ impl Game {
         for (id, _player) in self.players.iter() {
             let position = self.positions.get(id).unwrap();
             let rect = Rect::new(
-                position.x as i32 - PLAYER_VISUAL_WIDTH / 2,
-                position.y as i32 - PLAYER_VISUAL_WIDTH / 2,
+                position.x.to_int() as i32 - PLAYER_VISUAL_WIDTH / 2,
+                position.y.to_int() as i32 - PLAYER_VISUAL_WIDTH / 2,
                 PLAYER_VISUAL_WIDTH as u32,
                 PLAYER_VISUAL_WIDTH as u32,
             );
@@ -157,8 +178,8 @@ impl Game {
         for (_id, Minkle { drone_id, .. }) in self.minkles.iter() {
             if let Some(drone_pos) = self.positions.get(drone_id) {
                 let rect = Rect::new(
-                    drone_pos.x as i32 - PLAYER_VISUAL_WIDTH / 2,
-                    drone_pos.y as i32 - PLAYER_VISUAL_WIDTH / 2,
+                    drone_pos.x.to_int() as i32 - PLAYER_VISUAL_WIDTH / 2,
+                    drone_pos.y.to_int() as i32 - PLAYER_VISUAL_WIDTH / 2,
                     PLAYER_VISUAL_WIDTH as u32,
                     PLAYER_VISUAL_WIDTH as u32,
                 );
@@ -169,33 +190,320 @@ impl Game {
     }
 }
 
+#[derive(Debug)]
+pub enum RollbackError {
+    /// The command is for a frame that has already been pruned, so it can no longer be rolled
+    /// back to.
+    OutsideRollbackWindow { time: u64, oldest_time: u64 },
+    /// The player already acknowledged every command before `confirmed_frame`, so this one breaks
+    /// that promise.
+    BeforeConfirmedFrame { time: u64, confirmed_frame: u64 },
+}
+impl fmt::Display for RollbackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RollbackError::OutsideRollbackWindow { time, oldest_time } => write!(
+                f,
+                "command for frame {} is older than the oldest retained frame {}",
+                time, oldest_time
+            ),
+            RollbackError::BeforeConfirmedFrame {
+                time,
+                confirmed_frame,
+            } => write!(
+                f,
+                "command for frame {} arrived after frame {} was confirmed",
+                time, confirmed_frame
+            ),
+        }
+    }
+}
+impl std::error::Error for RollbackError {}
+
 pub struct RollbackableGame {
-    pub current_time: u64,
+    current_time: u64,
+    /// The furthest frame simulated so far. `current_time` is only behind it after a rollback,
+    /// until the frames in between are simulated again.
+    latest_time: u64,
+    /// Oldest frame still held in `frames`; everything before it has been pruned.
+    oldest_time: u64,
+    /// How many frames from before the slowest peer's confirmed input we keep around. Frames after
+    /// it can still be rolled back to, so they're always kept.
+    rollback_window: u64,
+    /// For each remote player, the frame before which all of their commands have been received.
+    confirmed_frames: IdHashMap<u64>,
+    /// First confirmed frame not yet handed out by `take_newly_confirmed`.
+    next_unreported_time: u64,
     frames: TimeMap<Game>,
     commands: TimeMap<Vec<(GameObjectId, Command)>>,
+    /// For each frame stepped from before every remote player's commands for it had arrived, the
+    /// commands assumed for each of those players, predicted or otherwise.
+    assumed_commands: TimeMap<IdHashMap<Vec<Command>>>,
+    /// Without one, remote players are assumed to have issued no new commands, so they carry on
+    /// doing whatever their last one had them doing.
+    predictor: Option<Box<dyn InputPredictor>>,
 }
 
 impl RollbackableGame {
-    pub fn new(starting_game: Game) -> Self {
+    pub fn new(starting_game: Game, rollback_window: u64) -> Self {
+        RollbackableGame::new_at(starting_game, 0, rollback_window)
+    }
+    /// Starts from `starting_game` as frame `time`, e.g. when joining a match already underway.
+    pub fn new_at(starting_game: Game, time: u64, rollback_window: u64) -> Self {
         let mut frames = new_time_map();
-        frames.insert(0, starting_game);
+        frames.insert(time, starting_game);
         RollbackableGame {
-            current_time: 0,
+            current_time: time,
+            latest_time: time,
+            oldest_time: time,
+            rollback_window,
+            confirmed_frames: new_id_hashmap(),
+            next_unreported_time: time,
             frames,
             commands: new_time_map(),
+            assumed_commands: new_time_map(),
+            predictor: None,
         }
     }
+    /// Starts over from `starting_game` as frame `time`, forgetting every peer, frame and command
+    /// but keeping the predictor.
+    pub fn reset_to(&mut self, starting_game: Game, time: u64) {
+        let predictor = self.predictor.take();
+        *self = RollbackableGame::new_at(starting_game, time, self.rollback_window);
+        self.predictor = predictor;
+    }
+    /// Guesses remote players' commands with `predictor` from now on, rather than assuming they
+    /// issued none.
+    pub fn set_predictor(&mut self, predictor: Box<dyn InputPredictor>) {
+        self.predictor = Some(predictor);
+    }
     pub fn draw<T: RenderTarget>(&self, canvas: &mut Canvas<T>) {
         self.current_frame().draw(canvas);
     }
+    /// The frame `current_frame` is for.
+    pub fn current_time(&self) -> u64 {
+        self.current_time
+    }
+    /// The furthest frame simulated so far, which is ahead of `current_time` after a rollback until
+    /// `advance_to` catches up again.
+    pub fn latest_time(&self) -> u64 {
+        self.latest_time
+    }
     pub fn current_frame(&self) -> &Game {
         self.frames
             .get(&self.current_time)
             .expect("Current frame not present!")
     }
-    pub fn add_command(&mut self, player_id: GameObjectId, command: Command, time: u64) {
+    /// The oldest frame that hasn't been pruned yet.
+    pub fn oldest_time(&self) -> u64 {
+        self.oldest_time
+    }
+    pub fn frame(&self, time: u64) -> Option<&Game> {
+        self.frames.get(&time)
+    }
+    /// Every command scheduled for `time` or later, in the order they are applied.
+    pub fn commands_from(&self, time: u64) -> Vec<(u64, GameObjectId, Command)> {
+        let mut times: Vec<_> = self
+            .commands
+            .keys()
+            .filter(|t| **t >= time)
+            .copied()
+            .collect();
+        times.sort();
+        times
+            .into_iter()
+            .flat_map(|time| {
+                self.commands[&time]
+                    .iter()
+                    .map(move |(player_id, command)| (time, *player_id, command.clone()))
+            })
+            .collect()
+    }
+    /// Registers a remote player whose commands have to arrive before frames can be confirmed.
+    pub fn add_peer(&mut self, player_id: GameObjectId) {
+        self.add_peer_from(player_id, self.oldest_time);
+    }
+    /// Registers a remote player who won't send any commands for frames before `frame`.
+    pub fn add_peer_from(&mut self, player_id: GameObjectId, frame: u64) {
+        self.confirmed_frames.insert(player_id, frame);
+    }
+    /// Stops waiting for a remote player's commands, e.g. once they've disconnected.
+    pub fn remove_peer(&mut self, player_id: GameObjectId) {
+        if let Some(confirmed_frame) = self.confirmed_frames.remove(&player_id) {
+            // Whatever of theirs has arrived is all there will be, so anything predicted beyond
+            // that was wrong
+            self.check_assumptions(player_id, confirmed_frame, self.current_time);
+        }
+    }
+    /// The frame a remote player has promised not to send any commands before, or `None` if they
+    /// aren't a peer.
+    pub fn peer_frame(&self, player_id: GameObjectId) -> Option<u64> {
+        self.confirmed_frames.get(&player_id).copied()
+    }
+    /// The last frame that is final: it and every frame before it were simulated with every
+    /// command issued for them, so none of them can change. Later frames still might.
+    pub fn confirmed_time(&self) -> u64 {
+        self.confirmed_frames
+            .values()
+            .copied()
+            .min()
+            .unwrap_or(self.current_time)
+            .min(self.current_time)
+    }
+    pub fn acknowledge(&mut self, player_id: GameObjectId, frame: u64) {
+        if let Some(confirmed_frame) = self.confirmed_frames.get_mut(&player_id) {
+            let previous_frame = *confirmed_frame;
+            *confirmed_frame = previous_frame.max(frame);
+            self.check_assumptions(player_id, previous_frame, frame);
+        }
+    }
+    /// Schedules a command from a remote player, rolling back to the frame it's for if that was
+    /// simulated assuming other commands from them. Nothing is simulated again until `advance_to`,
+    /// so several late commands only cost one rollback.
+    pub fn add_remote_command(
+        &mut self,
+        player_id: GameObjectId,
+        command: Command,
+        time: u64,
+    ) -> Result<(), RollbackError> {
+        let previous_frame = self.peer_frame(player_id).unwrap_or(time);
+        self.add_command(player_id, command, time)?;
+        self.check_assumptions(player_id, previous_frame, time + 1);
+        Ok(())
+    }
+    /// Simulates up to frame `time`, returning how many of the frames on the way had already been
+    /// simulated once before being rolled back.
+    pub fn advance_to(&mut self, time: u64) -> u64 {
+        let mut resimulated = 0;
+        while self.current_time < time {
+            if self.current_time < self.latest_time {
+                resimulated += 1;
+            }
+            self.step();
+        }
+        resimulated
+    }
+    /// Frames that have become final since the last call, e.g. to checksum them. Frames that were
+    /// pruned before being confirmed are skipped.
+    pub fn take_newly_confirmed(&mut self) -> Vec<(u64, Game)> {
+        let confirmed_time = self.confirmed_time();
+        let mut newly_confirmed = Vec::new();
+        while self.next_unreported_time <= confirmed_time {
+            if let Some(frame) = self.frames.get(&self.next_unreported_time) {
+                newly_confirmed.push((self.next_unreported_time, frame.clone()));
+            }
+            self.next_unreported_time += 1;
+        }
+        newly_confirmed
+    }
+    /// Whether a command from `player_id` could still be scheduled for `time`, without
+    /// scheduling it.
+    pub fn check_command_time(
+        &self,
+        player_id: GameObjectId,
+        time: u64,
+    ) -> Result<(), RollbackError> {
+        if time < self.oldest_time {
+            return Err(RollbackError::OutsideRollbackWindow {
+                time,
+                oldest_time: self.oldest_time,
+            });
+        }
+        match self.confirmed_frames.get(&player_id) {
+            Some(confirmed_frame) if time < *confirmed_frame => {
+                Err(RollbackError::BeforeConfirmedFrame {
+                    time,
+                    confirmed_frame: *confirmed_frame,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+    pub fn add_command(
+        &mut self,
+        player_id: GameObjectId,
+        command: Command,
+        time: u64,
+    ) -> Result<(), RollbackError> {
+        self.check_command_time(player_id, time)?;
+        if let Some(confirmed_frame) = self.confirmed_frames.get_mut(&player_id) {
+            // Commands arrive in order, so nothing earlier than this one is still to come
+            *confirmed_frame = time;
+        }
         let existing_commands = self.commands.entry(time).or_insert_with(Vec::new);
         existing_commands.push((player_id, command));
+        Ok(())
+    }
+    /// Unschedules a command `add_command` scheduled, rolling back to the frame it was for if that
+    /// has already been simulated. Like `add_remote_command`, nothing is simulated again until
+    /// `advance_to`.
+    pub fn remove_command(
+        &mut self,
+        player_id: GameObjectId,
+        command: &Command,
+        time: u64,
+    ) -> Result<(), RollbackError> {
+        if time < self.oldest_time {
+            return Err(RollbackError::OutsideRollbackWindow {
+                time,
+                oldest_time: self.oldest_time,
+            });
+        }
+        if let Some(commands) = self.commands.get_mut(&time) {
+            let position = commands
+                .iter()
+                .position(|(issuer_id, issued)| *issuer_id == player_id && issued == command);
+            if let Some(position) = position {
+                commands.remove(position);
+                if time < self.current_time {
+                    self.roll_back_to(time);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Every command `player_id` is known to have issued for `time`, in the order they arrived.
+    fn commands_of(&self, player_id: GameObjectId, time: u64) -> Vec<Command> {
+        self.commands
+            .get(&time)
+            .map(|commands| {
+                commands
+                    .iter()
+                    .filter(|(issuer_id, _)| *issuer_id == player_id)
+                    .map(|(_, command)| command.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    /// Rolls back to the first frame from `from` up to but not including `until` that was stepped
+    /// from assuming other commands from `player_id` than the ones we know of now, if any.
+    fn check_assumptions(&mut self, player_id: GameObjectId, from: u64, until: u64) {
+        let until = until.min(self.current_time);
+        for time in from.max(self.oldest_time)..until {
+            let assumed = self
+                .assumed_commands
+                .get(&time)
+                .and_then(|assumed_commands| assumed_commands.get(&player_id));
+            let known = self.commands_of(player_id, time);
+            let is_correct = match assumed {
+                Some(assumed) => *assumed == known,
+                // They weren't a peer yet, so nothing was expected of them
+                None => known.is_empty(),
+            };
+            if !is_correct {
+                self.roll_back_to(time);
+                return;
+            }
+        }
+    }
+    /// Makes `time` the current frame again, throwing away every frame after it so nothing
+    /// simulated from the wrong commands can be seen.
+    fn roll_back_to(&mut self, time: u64) {
+        while self.current_time > time {
+            self.frames.remove(&self.current_time);
+            self.current_time -= 1;
+            self.assumed_commands.remove(&self.current_time);
+        }
     }
     pub fn step(&mut self) {
         let mut next_frame = self.current_frame().clone();
@@ -204,8 +512,151 @@ impl RollbackableGame {
                 command.apply(&mut next_frame, *player_id)
             }
         }
+        let mut assumed_commands = new_id_hashmap();
+        for (player_id, confirmed_frame) in self.confirmed_frames.iter() {
+            if self.current_time < *confirmed_frame {
+                continue;
+            }
+            // Any of their commands that have arrived for this frame are all of them, as they're
+            // sent together
+            let mut assumed = self.commands_of(*player_id, self.current_time);
+            if assumed.is_empty() {
+                if let Some(predictor) = self.predictor.as_ref() {
+                    assumed = predictor.predict(self.current_frame(), *player_id);
+                    for command in assumed.iter() {
+                        command.apply(&mut next_frame, *player_id);
+                    }
+                }
+            }
+            assumed_commands.insert(*player_id, assumed);
+        }
+        if assumed_commands.is_empty() {
+            self.assumed_commands.remove(&self.current_time);
+        } else {
+            self.assumed_commands
+                .insert(self.current_time, assumed_commands);
+        }
         next_frame.step();
         self.current_time += 1;
+        self.latest_time = self.latest_time.max(self.current_time);
         self.frames.insert(self.current_time, next_frame);
+        self.prune();
+    }
+    fn prune(&mut self) {
+        // Only frames no peer's commands can reach any more are dropped. How far past those we
+        // get is up to whoever steps us, e.g. by stalling for a lagging peer
+        let new_oldest_time = self.confirmed_time().saturating_sub(self.rollback_window);
+        while self.oldest_time < new_oldest_time {
+            self.frames.remove(&self.oldest_time);
+            self.commands.remove(&self.oldest_time);
+            self.assumed_commands.remove(&self.oldest_time);
+            self.oldest_time += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PLAYER_SPEED;
+
+    /// Predicts every player keeps issuing the same command.
+    struct Repeat(Command);
+    impl InputPredictor for Repeat {
+        fn predict(&self, _game: &Game, _player_id: GameObjectId) -> Vec<Command> {
+            vec![self.0.clone()]
+        }
+    }
+
+    /// A game with one remote player in it, and that player.
+    fn game_with_peer() -> (RollbackableGame, GameObjectId) {
+        let mut game = Game::new();
+        let player_id = Player::new(&mut game, Fixed::from_int(100), Fixed::from_int(100));
+        let mut rollbackable_game = RollbackableGame::new(game, 16);
+        rollbackable_game.add_peer(player_id);
+        (rollbackable_game, player_id)
+    }
+
+    #[test]
+    fn commands_matching_the_prediction_resimulate_nothing() {
+        let command = Command::MoveByCommand(PLAYER_SPEED, Fixed::ZERO);
+        let (mut game, player_id) = game_with_peer();
+        game.set_predictor(Box::new(Repeat(command.clone())));
+        game.advance_to(10);
+        for time in 0..5 {
+            game.add_remote_command(player_id, command.clone(), time)
+                .unwrap();
+        }
+        assert_eq!(game.current_time(), 10);
+        assert_eq!(game.advance_to(11), 0);
+    }
+
+    #[test]
+    fn late_commands_cost_one_rollback_to_the_earliest() {
+        let late_commands = [
+            (4, Command::MoveByCommand(PLAYER_SPEED, Fixed::ZERO)),
+            (6, Command::MoveByCommand(-PLAYER_SPEED, Fixed::ZERO)),
+            (8, Command::MoveByCommand(Fixed::ZERO, Fixed::ZERO)),
+        ];
+        let (mut on_time, player_id) = game_with_peer();
+        for (time, command) in late_commands.iter() {
+            on_time
+                .add_remote_command(player_id, command.clone(), *time)
+                .unwrap();
+        }
+        on_time.advance_to(10);
+        let (mut late, player_id) = game_with_peer();
+        late.advance_to(10);
+        for (time, command) in late_commands.iter() {
+            late.add_remote_command(player_id, command.clone(), *time)
+                .unwrap();
+        }
+        assert_eq!(late.current_time(), 4);
+        assert_eq!(late.advance_to(10), 6);
+        assert_eq!(
+            late.current_frame().checksum(),
+            on_time.current_frame().checksum()
+        );
+    }
+
+    #[test]
+    fn prunes_everything_outside_the_rollback_window() {
+        let command = Command::MoveByCommand(PLAYER_SPEED, Fixed::ZERO);
+        let (mut game, player_id) = game_with_peer();
+        game.add_remote_command(player_id, command.clone(), 5)
+            .unwrap();
+        game.add_remote_command(player_id, command.clone(), 20)
+            .unwrap();
+        game.acknowledge(player_id, 30);
+        game.advance_to(40);
+        assert_eq!(game.confirmed_time(), 30);
+        assert_eq!(game.oldest_time(), 30 - 16);
+        assert!(game.frame(30 - 17).is_none());
+        assert!(game.frame(30 - 16).is_some());
+        let times: Vec<_> = game
+            .commands_from(0)
+            .into_iter()
+            .map(|(time, _, _)| time)
+            .collect();
+        assert_eq!(times, vec![20]);
+    }
+
+    #[test]
+    fn refuses_commands_older_than_the_rollback_window() {
+        let (mut game, player_id) = game_with_peer();
+        game.acknowledge(player_id, 30);
+        game.advance_to(40);
+        let result = game.add_command(
+            GameObjectId(12345),
+            Command::MoveByCommand(Fixed::ZERO, Fixed::ZERO),
+            13,
+        );
+        assert!(matches!(
+            result,
+            Err(RollbackError::OutsideRollbackWindow {
+                time: 13,
+                oldest_time: 14
+            })
+        ));
     }
 }
@@ -0,0 +1,22 @@
+use super::{commands::Command, fixed::Fixed, Game, GameObjectId};
+
+/// Guesses what a remote player did on a frame none of their commands have arrived for yet. A
+/// wrong guess only costs a rollback once their real commands arrive, so predictors don't have to
+/// agree between peers.
+pub trait InputPredictor {
+    /// The commands to apply for `player_id` when stepping on from `game`.
+    fn predict(&self, game: &Game, player_id: GameObjectId) -> Vec<Command>;
+}
+
+/// Assumes the player let go of everything, so stops them moving.
+pub struct NeutralInput;
+impl InputPredictor for NeutralInput {
+    fn predict(&self, game: &Game, player_id: GameObjectId) -> Vec<Command> {
+        match game.players.get(&player_id) {
+            Some(player) if player.dx != Fixed::ZERO || player.dy != Fixed::ZERO => {
+                vec![Command::MoveByCommand(Fixed::ZERO, Fixed::ZERO)]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
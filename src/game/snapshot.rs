@@ -0,0 +1,71 @@
+use std::io::{self, ErrorKind};
+
+use serde::{Deserialize, Serialize};
+
+use super::*;
+
+/// Bumped whenever the layout of `Game` or any of its components changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// The serialized form of a `Game`, which follows its version number. Components are listed in id
+/// order rather than hash map order, so the same state always gives the same bytes.
+#[derive(Serialize, Deserialize)]
+struct GameSnapshot {
+    id_counter: u64,
+    positions: Vec<(GameObjectId, Position)>,
+    players: Vec<(GameObjectId, Player)>,
+    gravity_affected: Vec<(GameObjectId, GravityAffected)>,
+    characters: Vec<(GameObjectId, Character)>,
+    minkles: Vec<(GameObjectId, Minkle)>,
+}
+
+fn sorted_components<V: Clone>(map: &IdHashMap<V>) -> Vec<(GameObjectId, V)> {
+    let mut components: Vec<_> = map.iter().map(|(id, v)| (*id, v.clone())).collect();
+    components.sort_by_key(|(id, _)| *id);
+    components
+}
+fn to_id_hashmap<V>(components: Vec<(GameObjectId, V)>) -> IdHashMap<V> {
+    let mut map = new_id_hashmap();
+    map.extend(components);
+    map
+}
+
+impl Game {
+    /// Everything needed to recreate this exact state with `load_state`, as postcard bytes.
+    pub fn save_state(&self) -> Vec<u8> {
+        let snapshot = GameSnapshot {
+            id_counter: self.id_counter,
+            positions: sorted_components(&self.positions),
+            players: sorted_components(&self.players),
+            gravity_affected: sorted_components(&self.gravity_affected),
+            characters: sorted_components(&self.characters),
+            minkles: sorted_components(&self.minkles),
+        };
+        postcard::to_stdvec(&(SNAPSHOT_VERSION, snapshot))
+            .expect("Game snapshot couldn't be serialized")
+    }
+    pub fn load_state(bytes: &[u8]) -> io::Result<Game> {
+        // Check the version before anything else, as a different version may not even parse
+        let (version, rest): (u32, _) = postcard::take_from_bytes(bytes)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        if version != SNAPSHOT_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Snapshot is version {}, only version {} is supported",
+                    version, SNAPSHOT_VERSION
+                ),
+            ));
+        }
+        let snapshot: GameSnapshot =
+            postcard::from_bytes(rest).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok(Game {
+            id_counter: snapshot.id_counter,
+            positions: to_id_hashmap(snapshot.positions),
+            players: to_id_hashmap(snapshot.players),
+            gravity_affected: to_id_hashmap(snapshot.gravity_affected),
+            characters: to_id_hashmap(snapshot.characters),
+            minkles: to_id_hashmap(snapshot.minkles),
+        })
+    }
+}
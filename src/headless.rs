@@ -0,0 +1,455 @@
+use std::{
+    io::{self, ErrorKind},
+    sync::mpsc::{self, Receiver, Sender},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    game::{
+        commands::{AbilityId, Command, RoutedMessage, SetInputDelay, SlotAssignment},
+        fixed::Fixed,
+        prediction::NeutralInput,
+        Game,
+    },
+    generate_move_command,
+    network::{
+        client::client_net_thread,
+        host::host_net_thread,
+        memory::MemoryTransport,
+        simulated::{simulated_transports, LinkConditions, SimulationRng},
+        spawn_network_thread,
+        spectators::{spectate, SpectatorUpdate},
+        Transport,
+    },
+    session::{setup_game, Session, SessionEvent},
+    spectator::Spectator,
+    KeyState, TICK_TIME, WINDOW_HEIGHT, WINDOW_WIDTH,
+};
+
+/// How long to wait after the last frame for every command to be confirmed on both sides.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Plays a match between scripted peers in this process, each client connected to the host in
+/// memory or over its own simulated link, with a spectator joining halfway. Reports whether
+/// everyone finished in the same state, which shouldn't depend on how they predict each other's
+/// inputs, so every other client predicts neutral input.
+pub fn simulate(
+    player_count: usize,
+    frames: u64,
+    seed: u64,
+    conditions: LinkConditions,
+    max_prediction: u64,
+) -> bool {
+    let is_perfect_link = conditions.latency.is_zero()
+        && conditions.jitter.is_zero()
+        && conditions.loss == 0.0
+        && conditions.duplication == 0.0
+        && conditions.reordering == 0.0;
+    let link = |link_seed: u64| -> (Box<dyn Transport>, Box<dyn Transport>) {
+        if is_perfect_link {
+            let (host, other) = MemoryTransport::pair();
+            (Box::new(host), Box::new(other))
+        } else {
+            let (host, other) = simulated_transports(conditions.clone(), link_seed);
+            (Box::new(host), Box::new(other))
+        }
+    };
+    let mut host_connections = Vec::new();
+    let mut peers = Vec::new();
+    for slot in 1..player_count as u64 {
+        let (host_connection, client_connection) = link(seed.wrapping_add(slot));
+        host_connections.push(host_connection);
+        let client_seed = seed ^ slot.wrapping_mul(0x9e3779b97f4a7c15);
+        peers.push(spawn_network_thread(move || {
+            let name = format!("client {}", slot);
+            let mut client_connection = Some(client_connection);
+            let setup = client_net_thread(name.clone(), move || {
+                client_connection
+                    .take()
+                    .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "Host has gone"))
+            });
+            run_scripted_peer(&name, setup, None, frames, max_prediction, client_seed)
+        }));
+    }
+    let (host_spectator_connection, spectator_connection) = link(!seed);
+    peers.insert(
+        0,
+        spawn_network_thread(move || {
+            let mut spectator_connection = Some(host_spectator_connection);
+            let (
+                slot_assignment,
+                set_input_delay,
+                start,
+                to_other_sender,
+                from_other_receiver,
+                spectator_sender,
+            ) = host_net_thread("host".to_string(), host_connections, 0, move || {
+                spectator_connection
+                    .take()
+                    .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "No more spectators"))
+            });
+            let setup = (
+                slot_assignment,
+                set_input_delay,
+                start,
+                to_other_sender,
+                from_other_receiver,
+            );
+            run_scripted_peer(
+                "host",
+                setup,
+                Some(spectator_sender),
+                frames,
+                max_prediction,
+                seed,
+            )
+        }),
+    );
+    let spectator = spawn_network_thread(move || run_spectator(spectator_connection, frames));
+    let mut checksums: Vec<u64> = peers
+        .into_iter()
+        .map(|peer| peer.join().expect("Peer panicked"))
+        .collect();
+    for (slot, checksum) in checksums.iter().enumerate() {
+        println!(
+            "After {} frames player {} has checksum {:016x}",
+            frames,
+            slot + 1,
+            checksum
+        );
+    }
+    let spectator_checksum = spectator.join().expect("Spectator panicked");
+    println!(
+        "After {} frames the spectator has checksum {:016x}",
+        frames, spectator_checksum
+    );
+    checksums.push(spectator_checksum);
+    checksums.iter().all(|checksum| *checksum == checksums[0])
+}
+
+/// Joins halfway through the match and watches it until it has seen all `frames` frames,
+/// returning the checksum of the last.
+fn run_spectator(connection: Box<dyn Transport>, frames: u64) -> u64 {
+    sleep(frames as u32 / 2 * TICK_TIME);
+    let (spectator_start, confirmed_receiver) = spectate("spectator".to_string(), connection);
+    let (_, player_ids) = setup_game(spectator_start.player_names.len());
+    let starting_game = Game::load_state(&spectator_start.state)
+        .unwrap_or_else(|e| panic!("Spectator couldn't load the host's game state: {}", e));
+    let mut spectator = Spectator::new(
+        starting_game,
+        spectator_start.frame,
+        player_ids,
+        confirmed_receiver,
+    );
+    let start = Instant::now();
+    while spectator.received_until() < frames {
+        if start.elapsed() > frames as u32 * TICK_TIME + SETTLE_TIMEOUT {
+            panic!(
+                "Spectator only received up to frame {} of {}",
+                spectator.received_until(),
+                frames
+            );
+        }
+        spectator
+            .tick()
+            .unwrap_or_else(|e| panic!("Spectator couldn't follow the match: {}", e));
+        sleep(TICK_TIME);
+    }
+    spectator.catch_up();
+    spectator.game().current_frame().checksum()
+}
+
+/// Runs one peer for `frames` frames with random inputs, then waits for the other peers to catch
+/// up and returns the checksum of the final, fully confirmed frame.
+fn run_scripted_peer(
+    my_name: &str,
+    (slot_assignment, set_input_delay, start, to_other_sender, from_other_receiver): (
+        SlotAssignment,
+        SetInputDelay,
+        Instant,
+        Sender<RoutedMessage>,
+        Receiver<RoutedMessage>,
+    ),
+    spectator_sender: Option<Sender<SpectatorUpdate>>,
+    frames: u64,
+    max_prediction: u64,
+    seed: u64,
+) -> u64 {
+    println!(
+        "{}: Input delay is {} frames",
+        my_name, set_input_delay.input_delay
+    );
+    let (starting_game, player_ids) = setup_game(slot_assignment.player_names.len());
+    let mut session = Session::new(
+        starting_game,
+        player_ids,
+        slot_assignment.slot,
+        set_input_delay.input_delay,
+        to_other_sender,
+        from_other_receiver,
+    );
+    session.leave_open(&slot_assignment.open_slots);
+    if slot_assignment.match_underway {
+        session.join_underway();
+    }
+    if let Some(spectator_sender) = spectator_sender {
+        session.stream_to_spectators(spectator_sender);
+    }
+    session.set_max_prediction(max_prediction);
+    if slot_assignment.slot % 2 == 1 {
+        session.set_input_predictor(Box::new(NeutralInput));
+    }
+    let (event_sender, event_receiver) = mpsc::channel();
+    session.send_events_to(event_sender);
+    let mut rng = SimulationRng::new(seed);
+    let mut key_state = KeyState::new();
+    sleep(start.saturating_duration_since(Instant::now()));
+    while session.game().current_time() < frames {
+        let tick_start = Instant::now();
+        let commands = scripted_commands(&mut rng, &mut key_state);
+        session.tick(commands);
+        report_events(my_name, &event_receiver);
+        let tick_time = session.tick_time();
+        let time_passed = tick_start.elapsed();
+        if time_passed < tick_time {
+            sleep(tick_time - time_passed);
+        }
+    }
+    let settle_start = Instant::now();
+    loop {
+        session.poll();
+        report_events(my_name, &event_receiver);
+        if session.game().confirmed_time() >= frames {
+            break;
+        }
+        if settle_start.elapsed() > SETTLE_TIMEOUT {
+            panic!(
+                "{} only confirmed up to frame {} of {}",
+                my_name,
+                session.game().confirmed_time(),
+                frames
+            );
+        }
+        sleep(Duration::from_millis(1));
+    }
+    session.game().current_frame().checksum()
+}
+
+fn report_events(my_name: &str, event_receiver: &Receiver<SessionEvent>) {
+    for event in event_receiver.try_iter() {
+        match event {
+            SessionEvent::Desync(slot, desync) => {
+                eprintln!("{} against player {}: {}", my_name, slot + 1, desync)
+            }
+            SessionEvent::RollbackPerformed { .. } => {}
+            _ => println!("{}: {}", my_name, event),
+        }
+    }
+}
+
+/// Presses and releases movement keys now and then, and occasionally uses an ability somewhere
+/// in the window.
+fn scripted_commands(rng: &mut SimulationRng, key_state: &mut KeyState) -> Vec<Command> {
+    let mut commands = Vec::new();
+    if rng.chance(0.1) {
+        match rng.next_u64() % 4 {
+            0 => key_state.left = !key_state.left,
+            1 => key_state.right = !key_state.right,
+            2 => key_state.up = !key_state.up,
+            _ => key_state.down = !key_state.down,
+        }
+        commands.push(generate_move_command(key_state));
+    }
+    if rng.chance(0.02) {
+        let x = Fixed::from_int((rng.next_u64() % WINDOW_WIDTH as u64) as i64);
+        let y = Fixed::from_int((rng.next_u64() % WINDOW_HEIGHT as u64) as i64);
+        commands.push(Command::AbilityCommand(AbilityId(0), x, y));
+    }
+    commands
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{network::LatencyStats, MAX_PREDICTION};
+
+    /// Fails everything once `cut` is set, like a connection that's gone down.
+    struct CuttableTransport {
+        inner: Box<dyn Transport>,
+        cut: Arc<AtomicBool>,
+    }
+    impl CuttableTransport {
+        fn check(&self) -> io::Result<()> {
+            if self.cut.load(Ordering::SeqCst) {
+                Err(io::Error::new(ErrorKind::BrokenPipe, "Connection was cut"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+    impl Transport for CuttableTransport {
+        fn send(&mut self, message: &[u8]) -> io::Result<()> {
+            self.check()?;
+            self.inner.send(message)
+        }
+        fn recv(&mut self) -> io::Result<Vec<u8>> {
+            self.check()?;
+            let message = self.inner.recv()?;
+            self.check()?;
+            Ok(message)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.check()?;
+            self.inner.flush()
+        }
+        fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+            Ok(Box::new(CuttableTransport {
+                inner: self.inner.try_clone()?,
+                cut: self.cut.clone(),
+            }))
+        }
+        fn set_recv_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+            self.inner.set_recv_timeout(timeout)
+        }
+        fn latency(&self) -> LatencyStats {
+            self.inner.latency()
+        }
+    }
+
+    #[test]
+    fn two_peers_agree_in_memory() {
+        assert!(simulate(
+            2,
+            120,
+            1,
+            LinkConditions::default(),
+            MAX_PREDICTION
+        ));
+    }
+
+    #[test]
+    fn three_peers_agree_over_a_bad_link() {
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(30),
+            jitter: Duration::from_millis(10),
+            loss: 0.05,
+            duplication: 0.05,
+            reordering: 0.05,
+        };
+        assert!(simulate(3, 120, 2, conditions, MAX_PREDICTION));
+    }
+
+    #[test]
+    fn a_dropped_client_rejoins() {
+        let frames = 300;
+        let (host_connection, client_connection) = MemoryTransport::pair();
+        let cut = Arc::new(AtomicBool::new(false));
+        let mut client_connection: Option<Box<dyn Transport>> = Some(Box::new(CuttableTransport {
+            inner: Box::new(client_connection),
+            cut: cut.clone(),
+        }));
+        let (rejoin_sender, rejoin_receiver) = mpsc::channel::<Box<dyn Transport>>();
+        let host = spawn_network_thread(move || {
+            let (slot_assignment, set_input_delay, start, to_other_sender, from_other_receiver, _) =
+                host_net_thread(
+                    "host".to_string(),
+                    vec![Box::new(host_connection)],
+                    0,
+                    move || {
+                        rejoin_receiver
+                            .recv()
+                            .map_err(|_| io::Error::new(ErrorKind::NotConnected, "Client has gone"))
+                    },
+                );
+            let setup = (
+                slot_assignment,
+                set_input_delay,
+                start,
+                to_other_sender,
+                from_other_receiver,
+            );
+            run_scripted_peer("host", setup, None, frames, MAX_PREDICTION, 1)
+        });
+        let client = spawn_network_thread(move || {
+            let setup = client_net_thread("client".to_string(), move || {
+                if let Some(connection) = client_connection.take() {
+                    return Ok(connection);
+                }
+                let (host_connection, client_connection) = MemoryTransport::pair();
+                rejoin_sender
+                    .send(Box::new(host_connection))
+                    .map_err(|_| io::Error::new(ErrorKind::NotConnected, "Host has gone"))?;
+                Ok(Box::new(client_connection))
+            });
+            run_scripted_peer("client", setup, None, frames, MAX_PREDICTION, 2)
+        });
+        // A second for the countdown, then a while into the match
+        sleep(Duration::from_secs(1) + frames as u32 / 4 * TICK_TIME);
+        cut.store(true, Ordering::SeqCst);
+        assert_eq!(
+            host.join().expect("Host panicked"),
+            client.join().expect("Client panicked")
+        );
+    }
+
+    #[test]
+    fn a_late_joiner_catches_up() {
+        let frames = 300;
+        let (host_connection, client_connection) = MemoryTransport::pair();
+        let (late_sender, late_receiver) = mpsc::channel::<Box<dyn Transport>>();
+        let host = spawn_network_thread(move || {
+            let (slot_assignment, set_input_delay, start, to_other_sender, from_other_receiver, _) =
+                host_net_thread(
+                    "host".to_string(),
+                    vec![Box::new(host_connection)],
+                    1,
+                    move || {
+                        late_receiver
+                            .recv()
+                            .map_err(|_| io::Error::new(ErrorKind::NotConnected, "Nobody's left"))
+                    },
+                );
+            let setup = (
+                slot_assignment,
+                set_input_delay,
+                start,
+                to_other_sender,
+                from_other_receiver,
+            );
+            run_scripted_peer("host", setup, None, frames, MAX_PREDICTION, 1)
+        });
+        let client = spawn_network_thread(move || {
+            let mut client_connection: Option<Box<dyn Transport>> =
+                Some(Box::new(client_connection));
+            let setup = client_net_thread("client".to_string(), move || {
+                client_connection
+                    .take()
+                    .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "Host has gone"))
+            });
+            run_scripted_peer("client", setup, None, frames, MAX_PREDICTION, 2)
+        });
+        // A second for the countdown, then a while into the match
+        sleep(Duration::from_secs(1) + frames as u32 / 4 * TICK_TIME);
+        let (host_connection, late_connection) = MemoryTransport::pair();
+        late_sender.send(Box::new(host_connection)).unwrap();
+        let mut late_connection: Option<Box<dyn Transport>> = Some(Box::new(late_connection));
+        let late_joiner = spawn_network_thread(move || {
+            let setup = client_net_thread("late joiner".to_string(), move || {
+                late_connection
+                    .take()
+                    .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "Host has gone"))
+            });
+            assert!(setup.0.match_underway);
+            run_scripted_peer("late joiner", setup, None, frames, MAX_PREDICTION, 3)
+        });
+        let checksum = host.join().expect("Host panicked");
+        assert_eq!(client.join().expect("Client panicked"), checksum);
+        assert_eq!(late_joiner.join().expect("Late joiner panicked"), checksum);
+    }
+}
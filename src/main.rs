@@ -1,23 +1,39 @@
 use std::{
+    io,
     net::{TcpListener, TcpStream},
-    thread::sleep,
+    sync::mpsc::{self, Receiver},
+    thread::{self, sleep},
     time::{Duration, Instant},
 };
 
-use game::{
-    characters::Minkle,
-    commands::{Command, TimedCommand},
-    Game, Player, RollbackableGame, convert_coords_from_sdl_coords, Position,
-};
-use sdl2::keyboard::Keycode;
+use game::{commands::Command, convert_coords_from_sdl_coords, fixed::Fixed, Game, Position};
+use sdl2::{keyboard::Keycode, render::Canvas, video::Window, EventPump};
 
 mod game;
+mod headless;
 mod network;
-use network::net_thread;
+mod replay;
+mod session;
+mod spectator;
+use network::{
+    client::client_net_thread,
+    host::host_net_thread,
+    simulated::LinkConditions,
+    spectators::spectate,
+    tcp::TcpTransport,
+    udp::{UdpListener, UdpTransport},
+    Transport,
+};
+use replay::{Replay, ReplayHeader, ReplayRecorder, REPLAY_VERSION};
+use session::{setup_game, Session, SessionEvent};
+use spectator::Spectator;
 
 const WINDOW_WIDTH: u32 = 400;
 const WINDOW_HEIGHT: u32 = 400;
 const TICK_TIME: Duration = Duration::from_millis(1000 / 60);
+const ROLLBACK_WINDOW: u64 = 16;
+/// How many frames past a player's last input we predict before waiting for them, by default.
+const MAX_PREDICTION: u64 = 12;
 struct KeyState {
     left: bool,
     right: bool,
@@ -34,39 +50,41 @@ impl KeyState {
         }
     }
 }
-const PLAYER_SPEED: f64 = 4.0;
-const PLAYER_JUMP_SPEED: f64 = 20.0;
-const PLAYER_FLOAT_SPEED: f64 = 2.0;
-const PLAYER_FASTFALL_SPEED: f64 = 4.0;
+const PLAYER_SPEED: Fixed = Fixed::from_int(4);
+const PLAYER_JUMP_SPEED: Fixed = Fixed::from_int(20);
+const PLAYER_FLOAT_SPEED: Fixed = Fixed::from_int(2);
+const PLAYER_FASTFALL_SPEED: Fixed = Fixed::from_int(4);
 fn generate_move_command(key_state: &KeyState) -> Command {
     let dx = if key_state.left {
         if key_state.right {
-            0.0
+            Fixed::ZERO
         } else {
             -PLAYER_SPEED
         }
     } else if key_state.right {
         PLAYER_SPEED
     } else {
-        0.0
+        Fixed::ZERO
     };
     let dy = if key_state.down {
         if key_state.up {
-            0.0
+            Fixed::ZERO
         } else {
             -PLAYER_FASTFALL_SPEED
         }
     } else if key_state.up {
         PLAYER_FLOAT_SPEED
     } else {
-        0.0
+        Fixed::ZERO
     };
     Command::MoveByCommand(dx, dy)
 }
 
 fn format_usage_message(program_name: &str) -> String {
     format!(
-        "Usage: {} [player name] [(host [port]|(client [ip] [port])]",
+        "Usage: {0} [player name] [(host [port] [players] [open slots])|((client|spectate) [ip] [port])] [tcp|udp] [replay file]\n       \
+         {0} replay [file] [frame]\n       \
+         {0} simulate [players] [frames] [seed] [latency ms] [jitter ms] [loss %] [duplication %] [reordering %] [max prediction]",
         program_name
     )
 }
@@ -75,6 +93,100 @@ fn print_usage_and_quit(program_name: &str) -> ! {
     std::process::exit(-1);
 }
 
+fn connect(transport: &str, address: &str) -> io::Result<Box<dyn Transport>> {
+    if transport == "tcp" {
+        let host = TcpStream::connect(address)?;
+        Ok(Box::new(TcpTransport::new(host)?))
+    } else {
+        Ok(Box::new(UdpTransport::connect(address)?))
+    }
+}
+/// Passes on each line typed into the terminal, to be said to the other players.
+fn read_chat_lines() -> Receiver<String> {
+    let (chat_sender, chat_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines().map_while(Result::ok) {
+            if chat_sender.send(line).is_err() {
+                return;
+            }
+        }
+    });
+    chat_receiver
+}
+fn open_window() -> (Canvas<Window>, EventPump) {
+    let sdl2_system = sdl2::init().expect("Couldn't initialise SDL");
+    let video_subsystem = sdl2_system.video().expect("No video");
+    let mut window_builder =
+        video_subsystem.window("TIME FLOWS IN ONE DIRECTION", WINDOW_WIDTH, WINDOW_HEIGHT);
+    let window = window_builder
+        .opengl()
+        .build()
+        .expect("Could not create window!");
+    let canvas = window
+        .into_canvas()
+        .accelerated()
+        .build()
+        .expect("Could not create canvas!");
+    let event_pump = sdl2_system
+        .event_pump()
+        .expect("Could not obtain event pump!");
+    (canvas, event_pump)
+}
+fn run_spectator(mut spectator: Spectator) {
+    let (mut canvas, mut event_pump) = open_window();
+    'main: loop {
+        let tick_start = Instant::now();
+        for event in event_pump.poll_iter() {
+            match event {
+                sdl2::event::Event::Quit { .. } => break 'main,
+                sdl2::event::Event::Window {
+                    win_event: sdl2::event::WindowEvent::Close,
+                    ..
+                } => break 'main,
+                _ => {}
+            }
+        }
+        if let Err(e) = spectator.tick() {
+            println!("Stopped watching: {}", e);
+            break;
+        }
+        spectator.game().draw(&mut canvas);
+        let time_passed = tick_start.elapsed();
+        if time_passed < TICK_TIME {
+            let remaining = TICK_TIME - time_passed;
+            sleep(remaining);
+        }
+    }
+}
+
+fn run_replay(replay: Replay) {
+    let end_time = replay.end_time();
+    let mut game = replay.start().expect("Replay has a broken starting state");
+    let (mut canvas, mut event_pump) = open_window();
+    'main: loop {
+        let tick_start = Instant::now();
+        for event in event_pump.poll_iter() {
+            match event {
+                sdl2::event::Event::Quit { .. } => break 'main,
+                sdl2::event::Event::Window {
+                    win_event: sdl2::event::WindowEvent::Close,
+                    ..
+                } => break 'main,
+                _ => {}
+            }
+        }
+        if game.current_time() < end_time {
+            game.step();
+        }
+        game.draw(&mut canvas);
+        let time_passed = tick_start.elapsed();
+        if time_passed < TICK_TIME {
+            let remaining = TICK_TIME - time_passed;
+            sleep(remaining);
+        }
+    }
+}
+
 fn main() {
     let mut arguments = std::env::args().into_iter();
     let program_name = arguments
@@ -83,78 +195,215 @@ fn main() {
     let my_name = arguments
         .next()
         .unwrap_or_else(|| print_usage_and_quit(&program_name));
-    let host_or_client = arguments
+    if my_name == "replay" {
+        let path = arguments
+            .next()
+            .unwrap_or_else(|| print_usage_and_quit(&program_name));
+        let replay =
+            Replay::load(&path).unwrap_or_else(|e| panic!("Couldn't load replay {}: {}", path, e));
+        match arguments.next() {
+            Some(frame) => {
+                let frame = frame
+                    .parse()
+                    .unwrap_or_else(|_| print_usage_and_quit(&program_name));
+                let game = replay
+                    .simulate_to(frame)
+                    .expect("Replay has a broken starting state");
+                println!("Frame {} has checksum {:016x}", frame, game.checksum());
+                println!("{}", game.dump());
+            }
+            None => run_replay(replay),
+        }
+        return;
+    }
+    if my_name == "simulate" {
+        let mut numbers = arguments.map(|argument| {
+            argument
+                .parse::<u64>()
+                .unwrap_or_else(|_| print_usage_and_quit(&program_name))
+        });
+        let player_count = numbers.next().unwrap_or(2) as usize;
+        if player_count < 2 {
+            print_usage_and_quit(&program_name);
+        }
+        let frames = numbers.next().unwrap_or(600);
+        let seed = numbers.next().unwrap_or(0);
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(numbers.next().unwrap_or(0)),
+            jitter: Duration::from_millis(numbers.next().unwrap_or(0)),
+            loss: numbers.next().unwrap_or(0) as f64 / 100.0,
+            duplication: numbers.next().unwrap_or(0) as f64 / 100.0,
+            reordering: numbers.next().unwrap_or(0) as f64 / 100.0,
+        };
+        let max_prediction = numbers.next().unwrap_or(MAX_PREDICTION);
+        if max_prediction == 0 || max_prediction > ROLLBACK_WINDOW {
+            print_usage_and_quit(&program_name);
+        }
+        if headless::simulate(player_count, frames, seed, conditions, max_prediction) {
+            println!("All peers ended in the same state");
+            return;
+        } else {
+            println!("Peers ended in different states!");
+            std::process::exit(1);
+        }
+    }
+    let mut arguments = arguments.peekable();
+    let role = arguments
         .next()
         .unwrap_or_else(|| print_usage_and_quit(&program_name));
-    let (is_host, connection) = match host_or_client.as_str() {
+    let (address, player_count, open_slots) = match role.as_str() {
         "host" => {
             let port = arguments
                 .next()
                 .unwrap_or_else(|| print_usage_and_quit(&program_name));
-            let tcp_listener = TcpListener::bind(format!("0.0.0.0:{}", port))
-                .expect(&format!("Unable to bind to port {}", port));
-            let (client, _) = tcp_listener.accept().expect("Unable to accept client");
-            (true, client)
+            let mut next_count =
+                |default| match arguments.peek().map(|argument| argument.parse::<usize>()) {
+                    Some(Ok(count)) => {
+                        arguments.next();
+                        count
+                    }
+                    _ => default,
+                };
+            let player_count = next_count(2);
+            let open_slots = next_count(0);
+            if player_count < 2 {
+                print_usage_and_quit(&program_name);
+            }
+            (format!("0.0.0.0:{}", port), player_count, open_slots)
         }
-        "client" => {
+        "client" | "spectate" => {
             let ip = arguments
                 .next()
                 .unwrap_or_else(|| print_usage_and_quit(&program_name));
             let port = arguments
                 .next()
                 .unwrap_or_else(|| print_usage_and_quit(&program_name));
-            let address = format!("{}:{}", ip, port);
-            let host =
-                TcpStream::connect(&address).expect(&format!("Could not connect to {}", address));
-            (false, host)
+            (format!("{}:{}", ip, port), 0, 0)
         }
         other_string => {
             println!("{}", format_usage_message(&program_name));
-            panic!("Expect 'host' or 'client', got '{}'", other_string);
+            panic!(
+                "Expect 'host', 'client' or 'spectate', got '{}'",
+                other_string
+            );
         }
     };
-
-    let (their_handshake, set_input_delay, to_other_sender, from_other_receiver) =
-        net_thread(is_host, my_name.clone(), connection);
-
-    let mut starting_game = Game::new();
-    let player_ids = vec![
-        Player::new(&mut starting_game, 100.0, 100.0),
-        Player::new(&mut starting_game, 200.0, 100.0),
-    ];
-    Minkle::new(&mut starting_game, player_ids[0]);
-    if their_handshake.my_name == my_name {
-        panic!("Both players cannot have the same name!");
+    let transport = arguments.next().unwrap_or_else(|| "tcp".to_string());
+    let replay_path = arguments.next();
+    if transport != "tcp" && transport != "udp" {
+        println!("{}", format_usage_message(&program_name));
+        panic!("Expect 'tcp' or 'udp', got '{}'", transport);
     }
-    let (my_id, their_id) = if their_handshake.my_name < my_name {
-        let my_id = player_ids[0];
-        let their_id = player_ids[1];
-        (my_id, their_id)
+    if role == "spectate" {
+        let (spectator_start, confirmed_receiver) = spectate(
+            my_name,
+            connect(&transport, &address)
+                .unwrap_or_else(|e| panic!("Could not connect to {}: {}", address, e)),
+        );
+        let (_, player_ids) = setup_game(spectator_start.player_names.len());
+        let starting_game = Game::load_state(&spectator_start.state)
+            .unwrap_or_else(|e| panic!("Couldn't load the host's game state: {}", e));
+        run_spectator(Spectator::new(
+            starting_game,
+            spectator_start.frame,
+            player_ids,
+            confirmed_receiver,
+        ));
+        return;
+    }
+    let (setup, spectator_sender) = if role == "host" {
+        let mut accept: Box<dyn FnMut() -> io::Result<Box<dyn Transport>> + Send> =
+            if transport == "tcp" {
+                let tcp_listener = TcpListener::bind(&address)
+                    .unwrap_or_else(|e| panic!("Unable to bind to {}: {}", address, e));
+                Box::new(move || {
+                    let (client, _) = tcp_listener.accept()?;
+                    Ok(Box::new(TcpTransport::new(client)?) as Box<dyn Transport>)
+                })
+            } else {
+                let udp_listener = UdpListener::bind(&address)
+                    .unwrap_or_else(|e| panic!("Unable to bind to {}: {}", address, e));
+                Box::new(move || Ok(Box::new(udp_listener.accept()?) as Box<dyn Transport>))
+            };
+        let connections = (1..player_count)
+            .map(|_| accept().expect("Unable to accept client"))
+            .collect();
+        // Anyone who connects once every player has joined takes an open slot, spectates or
+        // rejoins
+        let (
+            slot_assignment,
+            set_input_delay,
+            start,
+            to_other_sender,
+            from_other_receiver,
+            spectator_sender,
+        ) = host_net_thread(my_name, connections, open_slots, accept);
+        (
+            (
+                slot_assignment,
+                set_input_delay,
+                start,
+                to_other_sender,
+                from_other_receiver,
+            ),
+            Some(spectator_sender),
+        )
     } else {
-        let my_id = player_ids[1];
-        let their_id = player_ids[0];
-        (my_id, their_id)
+        let connect_address = address.clone();
+        let connect_transport = transport.clone();
+        (
+            client_net_thread(my_name, move || {
+                connect(&connect_transport, &connect_address)
+            }),
+            None,
+        )
     };
-    let mut game = RollbackableGame::new(starting_game);
+    let (slot_assignment, set_input_delay, start, to_other_sender, from_other_receiver) = setup;
+    println!("Input delay is {} frames", set_input_delay.input_delay);
+    println!(
+        "Playing as player {} of {}",
+        slot_assignment.slot + 1,
+        slot_assignment.player_names.len()
+    );
 
-    let sdl2_system = sdl2::init().expect("Couldn't initialise SDL");
-    let video_subsystem = sdl2_system.video().expect("No video");
-    let mut window_builder =
-        video_subsystem.window("TIME FLOWS IN ONE DIRECTION", WINDOW_WIDTH, WINDOW_HEIGHT);
-    let window = window_builder
-        .opengl()
-        .build()
-        .expect("Could not create window!");
-    let mut canvas = window
-        .into_canvas()
-        .accelerated()
-        .build()
-        .expect("Could not create canvas!");
-    let mut event_pump = sdl2_system
-        .event_pump()
-        .expect("Could not obtain event pump!");
+    let (starting_game, player_ids) = setup_game(slot_assignment.player_names.len());
+    let mut session = Session::new(
+        starting_game,
+        player_ids,
+        slot_assignment.slot,
+        set_input_delay.input_delay,
+        to_other_sender,
+        from_other_receiver,
+    );
+    session.leave_open(&slot_assignment.open_slots);
+    if slot_assignment.match_underway {
+        session.join_underway();
+    }
+    if let Some(spectator_sender) = spectator_sender {
+        session.stream_to_spectators(spectator_sender);
+    }
+    let (event_sender, event_receiver) = mpsc::channel();
+    session.send_events_to(event_sender);
+    if slot_assignment.match_underway && replay_path.is_some() {
+        println!("Not recording a replay, as the match started without us");
+    } else if let Some(replay_path) = replay_path {
+        let header = ReplayHeader {
+            version: REPLAY_VERSION,
+            player_names: slot_assignment.player_names.clone(),
+            recorded_by: slot_assignment.slot,
+            input_delay: set_input_delay.input_delay,
+            starting_state: session.game().current_frame().save_state(),
+        };
+        let replay_recorder = ReplayRecorder::create(&replay_path, &header)
+            .unwrap_or_else(|e| panic!("Couldn't create replay {}: {}", replay_path, e));
+        session.record_to(replay_recorder);
+    }
 
+    let (mut canvas, mut event_pump) = open_window();
     let mut key_state = KeyState::new();
+    let chat_receiver = read_chat_lines();
+    // Everyone's frame 0 happens together, however long opening the window took
+    sleep(start.saturating_duration_since(Instant::now()));
 
     'main: loop {
         let tick_start = Instant::now();
@@ -198,9 +447,8 @@ fn main() {
                     x,
                     y,
                 } => {
-                    let Position {x: gx, y: gy} = convert_coords_from_sdl_coords(x, y);
-                    let command =
-                        Command::AbilityCommand(game::commands::AbilityId(0), gx, gy);
+                    let Position { x: gx, y: gy } = convert_coords_from_sdl_coords(x, y);
+                    let command = Command::AbilityCommand(game::commands::AbilityId(0), gx, gy);
                     new_commands.push(command);
                 }
                 sdl2::event::Event::KeyUp {
@@ -246,32 +494,26 @@ fn main() {
             let command = generate_move_command(&key_state);
             new_commands.push(command);
         }
-        for command in new_commands {
-            let time = game.current_time + set_input_delay.input_delay;
-            let timed_command = TimedCommand {
-                time,
-                command: command.clone(),
-            };
-            to_other_sender
-                .send(timed_command)
-                .expect("Couldn't send command to other player");
-            game.add_command(my_id, command, time);
+        for text in chat_receiver.try_iter() {
+            session.send_chat(text);
         }
-        for timed_command in from_other_receiver.try_iter() {
-            game.add_command(their_id, timed_command.command, timed_command.time);
-            if timed_command.time < game.current_time {
-                let until = game.current_time;
-                game.current_time = timed_command.time;
-                while game.current_time < until {
-                    game.step();
+        session.tick(new_commands);
+        for event in event_receiver.try_iter() {
+            match event {
+                SessionEvent::Desync(..) => eprintln!("{}", event),
+                // Far too common to be worth mentioning
+                SessionEvent::RollbackPerformed { .. } => {}
+                SessionEvent::PeerDisconnected(0) | SessionEvent::PeerTimedOut(0) => {
+                    println!("{}, the game is paused until we reconnect", event)
                 }
+                _ => println!("{}", event),
             }
         }
-        game.draw(&mut canvas);
-        game.step();
+        session.game().draw(&mut canvas);
+        let tick_time = session.tick_time();
         let time_passed = tick_start.elapsed();
-        if time_passed < TICK_TIME {
-            let remaining = TICK_TIME - time_passed;
+        if time_passed < tick_time {
+            let remaining = tick_time - time_passed;
             sleep(remaining);
         }
     }
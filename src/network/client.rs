@@ -0,0 +1,287 @@
+use std::{
+    io::{self, ErrorKind},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::game::commands::{
+    ConnectionNotice, HandshakeReply, PeerMessage, RejoinRequest, RoutedMessage, SetInputDelay,
+    SlotAssignment, StartCountdown,
+};
+
+use super::{
+    client_measure_timing, exchange_handshakes, spawn_network_thread, Transport,
+    HEARTBEAT_INTERVAL, PEER_TIMEOUT, REJOIN_FEATURE,
+};
+
+/// How long to wait between attempts to reconnect to the host.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The connection to the host, `None` while reconnecting.
+type SharedConnection = Arc<Mutex<Option<Box<dyn Transport>>>>;
+
+/// Joins the session hosted at the other end of whatever `connect` returns, along with when the
+/// host has said the match starts. If it already has, the slot assignment says so, and the host
+/// brings the session up to date once it's running. If that connection fails or goes quiet, the
+/// session gets `Disconnected` or `TimedOut` from the host's slot and `connect` is tried again
+/// until it works, after which the host brings the session back up to date. The session can also
+/// send `Disconnected` itself to have the connection dropped and made again.
+pub fn client_net_thread<F>(
+    my_name: String,
+    mut connect: F,
+) -> (
+    SlotAssignment,
+    SetInputDelay,
+    Instant,
+    Sender<RoutedMessage>,
+    Receiver<RoutedMessage>,
+)
+where
+    F: FnMut() -> io::Result<Box<dyn Transport>> + Send + 'static,
+{
+    let mut connection = connect().expect("Couldn't connect to host");
+    exchange_handshakes(&my_name, &[], &[], false, connection.as_mut())
+        .expect("Failed to exchange handshakes");
+    client_measure_timing(connection.as_mut()).expect("Unable to work with server to measure lag");
+    let slot_assignment: SlotAssignment = connection
+        .receive_item()
+        .expect("Unable to read slot assignment");
+    let set_input_delay: SetInputDelay = connection
+        .receive_item()
+        .expect("Unable to read input delay");
+    let start_countdown: StartCountdown = connection
+        .receive_item()
+        .expect("Unable to read start countdown");
+    let start = Instant::now() + Duration::from_micros(start_countdown.micros);
+    let rejoin_request = RejoinRequest {
+        slot: slot_assignment.slot,
+        rejoin_token: slot_assignment.rejoin_token,
+    };
+    let (from_other_sender, from_other_receiver) = mpsc::channel();
+    let (to_other_sender, to_other_receiver) = mpsc::channel();
+    let mut in_stream = connection
+        .try_clone()
+        .expect("Couldn't create input network stream");
+    in_stream
+        .set_recv_timeout(Some(PEER_TIMEOUT))
+        .expect("Couldn't set a timeout on the connection");
+    let shared_connection = Arc::new(Mutex::new(Some(connection)));
+    let output_connection = shared_connection.clone();
+    spawn_network_thread(move || {
+        reconnecting_input_thread(
+            my_name,
+            rejoin_request,
+            connect,
+            in_stream,
+            from_other_sender,
+            shared_connection,
+        )
+    });
+    spawn_network_thread(|| reconnecting_output_thread(to_other_receiver, output_connection));
+    (
+        slot_assignment,
+        set_input_delay,
+        start,
+        to_other_sender,
+        from_other_receiver,
+    )
+}
+/// Passes on everything the host sends, reconnecting whenever the connection fails or the output
+/// thread drops it.
+fn reconnecting_input_thread<F>(
+    my_name: String,
+    rejoin_request: RejoinRequest,
+    mut connect: F,
+    mut in_stream: Box<dyn Transport>,
+    message_sender: Sender<RoutedMessage>,
+    shared_connection: SharedConnection,
+) where
+    F: FnMut() -> io::Result<Box<dyn Transport>>,
+{
+    loop {
+        let mut result = in_stream.receive_item::<RoutedMessage>();
+        if result.is_ok() && shared_connection.lock().unwrap().is_none() {
+            result = Err(io::Error::new(
+                ErrorKind::ConnectionAborted,
+                "Connection was dropped",
+            ));
+        }
+        let message = match result {
+            Ok(RoutedMessage {
+                message: PeerMessage::Heartbeat,
+                ..
+            }) => continue,
+            Ok(RoutedMessage {
+                message: PeerMessage::Ping(ping),
+                ..
+            }) => {
+                // Answered straight away rather than by the session, so the host measures the
+                // network and not how often we poll
+                if let Some(connection) = shared_connection.lock().unwrap().as_mut() {
+                    let _ = connection
+                        .send_item(PeerMessage::Pong(ping))
+                        .and_then(|_| connection.flush());
+                }
+                continue;
+            }
+            Ok(message) => message,
+            Err(e) => {
+                *shared_connection.lock().unwrap() = None;
+                let disconnected = RoutedMessage {
+                    from: 0,
+                    message: if e.kind() == ErrorKind::TimedOut {
+                        PeerMessage::TimedOut
+                    } else {
+                        PeerMessage::Disconnected
+                    },
+                };
+                if message_sender.send(disconnected).is_err() {
+                    return;
+                }
+                let connection =
+                    reconnect(&my_name, &rejoin_request, &mut connect, &message_sender);
+                in_stream = match connection.try_clone() {
+                    Ok(in_stream) => in_stream,
+                    Err(e) => {
+                        report_reconnect_failure(&message_sender, e);
+                        continue;
+                    }
+                };
+                *shared_connection.lock().unwrap() = Some(connection);
+                continue;
+            }
+        };
+        if message_sender.send(message).is_err() {
+            return;
+        }
+    }
+}
+/// Tries `connect` until it gets us back into the match, telling the session each time it fails.
+fn reconnect<F>(
+    my_name: &str,
+    rejoin_request: &RejoinRequest,
+    connect: &mut F,
+    message_sender: &Sender<RoutedMessage>,
+) -> Box<dyn Transport>
+where
+    F: FnMut() -> io::Result<Box<dyn Transport>>,
+{
+    loop {
+        sleep(RECONNECT_INTERVAL);
+        let connection = connect().and_then(|mut connection| {
+            // Nothing may ever come back from a host that's gone, e.g. over UDP
+            connection.set_recv_timeout(Some(PEER_TIMEOUT))?;
+            exchange_handshakes(my_name, &[REJOIN_FEATURE], &[], false, connection.as_mut())?;
+            connection.send_item(rejoin_request.clone())?;
+            connection.flush()?;
+            match connection.receive_item()? {
+                HandshakeReply::Accepted => Ok(connection),
+                HandshakeReply::Rejected(rejection) => Err(io::Error::new(
+                    ErrorKind::ConnectionRefused,
+                    format!("Host refused to let us rejoin: {}", rejection),
+                )),
+            }
+        });
+        match connection {
+            Ok(connection) => return connection,
+            Err(e) => report_reconnect_failure(message_sender, e),
+        }
+    }
+}
+fn report_reconnect_failure(message_sender: &Sender<RoutedMessage>, e: io::Error) {
+    // A session that's gone has no use for it
+    let _ = message_sender.send(RoutedMessage {
+        from: 0,
+        message: PeerMessage::Notice(ConnectionNotice::ReconnectFailed(e.to_string())),
+    });
+}
+/// Sends our messages to the host, or a heartbeat if there's been nothing to send for a while,
+/// dropping them while there's no connection. Anything lost that way is superseded by the state
+/// the host sends when we rejoin. `Disconnected` from the session drops the connection instead.
+fn reconnecting_output_thread(
+    message_receiver: Receiver<RoutedMessage>,
+    shared_connection: SharedConnection,
+) {
+    loop {
+        let routed_message = match message_receiver.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(routed_message) => routed_message,
+            Err(RecvTimeoutError::Timeout) => RoutedMessage {
+                from: 0,
+                message: PeerMessage::Heartbeat,
+            },
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        let mut connection = shared_connection.lock().unwrap();
+        if matches!(routed_message.message, PeerMessage::Disconnected) {
+            // The input thread notices next time anything arrives, and takes care of reconnecting
+            *connection = None;
+            continue;
+        }
+        let out_stream = match connection.as_mut() {
+            Some(out_stream) => out_stream,
+            None => continue,
+        };
+        // The host already knows who we are, so only the message itself is sent
+        let sent = out_stream.send_item(routed_message.message).and_then(|_| {
+            for routed_message in message_receiver.try_iter() {
+                // However far back in the queue it is, `Disconnected` is never passed on
+                if matches!(routed_message.message, PeerMessage::Disconnected) {
+                    return Err(io::Error::new(
+                        ErrorKind::ConnectionAborted,
+                        "Session dropped the connection",
+                    ));
+                }
+                out_stream.send_item(routed_message.message)?;
+            }
+            out_stream.flush()
+        });
+        if sent.is_err() {
+            // The input thread notices too, and takes care of reconnecting
+            *connection = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game::commands::Chat, network::memory::MemoryTransport};
+
+    #[test]
+    fn disconnected_behind_other_messages_drops_the_connection() {
+        let (host, client) = MemoryTransport::pair();
+        let mut host: Box<dyn Transport> = Box::new(host);
+        let shared_connection: SharedConnection = Arc::new(Mutex::new(Some(Box::new(client))));
+        let (message_sender, message_receiver) = mpsc::channel();
+        // Both queued before the output thread starts, so they go out as one batch
+        for message in [
+            PeerMessage::Chat(Chat {
+                text: "bye".to_string(),
+            }),
+            PeerMessage::Disconnected,
+        ] {
+            message_sender
+                .send(RoutedMessage { from: 1, message })
+                .unwrap();
+        }
+        let output_connection = shared_connection.clone();
+        spawn_network_thread(move || {
+            reconnecting_output_thread(message_receiver, output_connection)
+        });
+        loop {
+            match host.receive_item::<PeerMessage>() {
+                Ok(PeerMessage::Chat(_)) => {}
+                Ok(message) => panic!("Host was sent {:?}", message),
+                Err(e) => {
+                    assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+                    break;
+                }
+            }
+        }
+        assert!(shared_connection.lock().unwrap().is_none());
+    }
+}
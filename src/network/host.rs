@@ -0,0 +1,621 @@
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::{BuildHasher, Hasher},
+    io::{self, ErrorKind},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::game::commands::{
+    ConnectionNotice, HandshakeReply, PeerMessage, Ping, PlayerSlot, Rejection, RejoinRequest,
+    RoundTrip, RoutedMessage, SetInputDelay, SlotAssignment, StartCountdown,
+};
+
+use super::{
+    exchange_handshakes, heartbeat_output_thread, host_measure_timing, spawn_network_thread,
+    spectators::{add_spectator, spectator_feed, SpectatorFeed, SpectatorUpdate},
+    Transport, PEER_TIMEOUT, PING_INTERVAL, REJOIN_FEATURE, SPECTATE_FEATURE, START_COUNTDOWN,
+};
+
+/// What the host lets clients request in their handshakes.
+const HOST_FEATURES: &[&str] = &[REJOIN_FEATURE, SPECTATE_FEATURE];
+
+/// The host's connection to each client, shared by every thread that talks to them.
+struct Clients {
+    /// Indexed by slot minus one, `None` while that client is disconnected.
+    senders: Vec<Option<Sender<RoutedMessage>>>,
+    /// Bumped whenever a client reconnects, so the threads for its old connection know to stop.
+    generations: Vec<u64>,
+    /// What each client has to show to rejoin, from its `SlotAssignment`. Open slots have one
+    /// ready for whoever joins in them.
+    rejoin_tokens: Vec<u64>,
+    /// Indexed by slot, the host's included. Empty for open slots.
+    player_names: Vec<String>,
+    /// Slots nobody has joined yet, lowest first.
+    open_slots: Vec<PlayerSlot>,
+    /// When each client was sent the pings it hasn't answered yet, oldest first.
+    pings: Vec<VecDeque<u64>>,
+    /// What ping times are measured from.
+    started: Instant,
+}
+
+/// Sets up a session with every client in `connections`, which get slots 1 and up in that order,
+/// and counts them all down to the same moment to start at, which is returned too. From then on
+/// whatever the host's session sends is passed on to every client other than the one it came from,
+/// so the session itself decides what gets relayed.
+///
+/// The match has `open_slots` more slots after theirs, for players joining once it's underway.
+/// Later connections from `accept` can be those players, spectators, or players rejoining with
+/// the token from their slot assignment. Spectators are streamed whatever is sent into the last
+/// channel returned. `accept` is called until it fails with `BrokenPipe` or `NotConnected`.
+pub fn host_net_thread<F>(
+    my_name: String,
+    mut connections: Vec<Box<dyn Transport>>,
+    open_slots: usize,
+    accept: F,
+) -> (
+    SlotAssignment,
+    SetInputDelay,
+    Instant,
+    Sender<RoutedMessage>,
+    Receiver<RoutedMessage>,
+    Sender<SpectatorUpdate>,
+)
+where
+    F: FnMut() -> io::Result<Box<dyn Transport>> + Send + 'static,
+{
+    let mut player_names = vec![my_name.clone()];
+    for connection in connections.iter_mut() {
+        let handshake =
+            exchange_handshakes(&my_name, &[], HOST_FEATURES, false, connection.as_mut())
+                .expect("Failed to exchange handshakes");
+        if player_names.contains(&handshake.my_name) {
+            panic!("Two players cannot have the same name!");
+        }
+        player_names.push(handshake.my_name);
+    }
+    let mut input_delay = 0;
+    let mut round_trips: Vec<Duration> = Vec::new();
+    for connection in connections.iter_mut() {
+        let (client_input_delay, round_trip) =
+            host_measure_timing(connection.as_mut()).expect("Unable to measure host timing");
+        input_delay = input_delay.max(client_input_delay);
+        round_trips.push(round_trip);
+    }
+    let set_input_delay = SetInputDelay { input_delay };
+    let client_count = connections.len() + open_slots;
+    let open_slots: Vec<PlayerSlot> = (player_names.len()..=client_count)
+        .map(|slot| slot as PlayerSlot)
+        .collect();
+    player_names.resize(client_count + 1, String::new());
+    let rejoin_tokens: Vec<u64> = (0..client_count)
+        .map(|_| RandomState::new().build_hasher().finish())
+        .collect();
+    for (index, connection) in connections.iter_mut().enumerate() {
+        connection
+            .send_item(SlotAssignment {
+                slot: index as PlayerSlot + 1,
+                player_names: player_names.clone(),
+                rejoin_token: rejoin_tokens[index],
+                open_slots: open_slots.clone(),
+                match_underway: false,
+            })
+            .expect("Unable to send slot assignment");
+        connection
+            .send_item(set_input_delay.clone())
+            .expect("Unable to send input delay");
+    }
+    let start = Instant::now() + START_COUNTDOWN;
+    for (connection, round_trip) in connections.iter_mut().zip(round_trips) {
+        // It takes about half a round trip to arrive
+        let countdown = start.saturating_duration_since(Instant::now() + round_trip / 2);
+        connection
+            .send_item(StartCountdown {
+                micros: countdown.as_micros() as u64,
+            })
+            .and_then(|_| connection.flush())
+            .expect("Unable to send start countdown");
+    }
+    let (from_other_sender, from_other_receiver) = mpsc::channel();
+    let (to_other_sender, to_other_receiver) = mpsc::channel();
+    let clients = Arc::new(Mutex::new(Clients {
+        senders: vec![None; client_count],
+        generations: vec![0; client_count],
+        pings: vec![VecDeque::new(); client_count],
+        rejoin_tokens,
+        player_names: player_names.clone(),
+        open_slots: open_slots.clone(),
+        started: Instant::now(),
+    }));
+    for (index, connection) in connections.into_iter().enumerate() {
+        connect_client(
+            &clients,
+            index as PlayerSlot + 1,
+            connection,
+            from_other_sender.clone(),
+            false,
+        )
+        .expect("Couldn't create input network stream");
+    }
+    let broadcast_clients = clients.clone();
+    spawn_network_thread(move || broadcast_thread(to_other_receiver, broadcast_clients));
+    let (feed, spectator_sender) = spectator_feed(player_names.clone());
+    spawn_network_thread(move || {
+        accept_late_connections(my_name, accept, clients, from_other_sender, feed)
+    });
+    (
+        SlotAssignment {
+            slot: 0,
+            player_names,
+            rejoin_token: 0,
+            open_slots,
+            match_underway: false,
+        },
+        set_input_delay,
+        start,
+        to_other_sender,
+        from_other_receiver,
+        spectator_sender,
+    )
+}
+
+/// Starts talking to the client in `slot` over `connection`, taking over from any connection it had
+/// before. A reconnecting client is announced to the host's session with `Reconnected`.
+fn connect_client(
+    clients: &Arc<Mutex<Clients>>,
+    slot: PlayerSlot,
+    connection: Box<dyn Transport>,
+    local_sender: Sender<RoutedMessage>,
+    is_reconnect: bool,
+) -> io::Result<()> {
+    let mut in_stream = connection.try_clone()?;
+    in_stream.set_recv_timeout(Some(PEER_TIMEOUT))?;
+    let (client_sender, client_receiver) = mpsc::channel();
+    let generation = {
+        let mut clients = clients.lock().unwrap();
+        let index = slot as usize - 1;
+        clients.generations[index] += 1;
+        clients.senders[index] = Some(client_sender);
+        clients.pings[index].clear();
+        if is_reconnect {
+            let _ = local_sender.send(RoutedMessage {
+                from: slot,
+                message: PeerMessage::Reconnected,
+            });
+        }
+        clients.generations[index]
+    };
+    let heartbeat = RoutedMessage {
+        from: 0,
+        message: PeerMessage::Heartbeat,
+    };
+    spawn_network_thread(|| heartbeat_output_thread(client_receiver, connection, heartbeat));
+    let ping_clients = clients.clone();
+    spawn_network_thread(move || ping_thread(slot, generation, ping_clients));
+    let clients = clients.clone();
+    spawn_network_thread(move || {
+        client_input_thread(slot, generation, in_stream, local_sender, clients)
+    });
+    Ok(())
+}
+/// Passes everything one client sends to the host's session, then `Disconnected` or `TimedOut`
+/// once their connection fails. Their answers to pings become `RoundTrip`s, and anything only the
+/// host's own network threads should make up is dropped.
+fn client_input_thread(
+    from: PlayerSlot,
+    generation: u64,
+    mut in_stream: Box<dyn Transport>,
+    local_sender: Sender<RoutedMessage>,
+    clients: Arc<Mutex<Clients>>,
+) {
+    let index = from as usize - 1;
+    loop {
+        let result = in_stream.receive_item::<PeerMessage>();
+        if matches!(result, Ok(PeerMessage::Heartbeat)) {
+            continue;
+        }
+        // Checked under the same lock as reconnects, so nothing from an old connection can follow
+        // `Reconnected`
+        let mut clients = clients.lock().unwrap();
+        if clients.generations[index] != generation {
+            return;
+        }
+        let message = match result {
+            Ok(PeerMessage::Pong(ping)) => {
+                // Only a ping the host actually sent can be answered, and the pings before it
+                // never will be
+                let pings = &mut clients.pings[index];
+                match pings.iter().position(|&sent_at| sent_at == ping.sent_at) {
+                    Some(position) => drop(pings.drain(..=position)),
+                    None => continue,
+                }
+                let now = clients.started.elapsed().as_micros() as u64;
+                PeerMessage::RoundTrip(RoundTrip {
+                    micros: now.saturating_sub(ping.sent_at),
+                })
+            }
+            Ok(
+                PeerMessage::RoundTrip(_)
+                | PeerMessage::Reconnected
+                | PeerMessage::Notice(_)
+                | PeerMessage::Disconnected
+                | PeerMessage::TimedOut,
+            ) => continue,
+            Ok(message) => message,
+            Err(e) => {
+                clients.senders[index] = None;
+                if e.kind() == ErrorKind::TimedOut {
+                    PeerMessage::TimedOut
+                } else {
+                    PeerMessage::Disconnected
+                }
+            }
+        };
+        let disconnected = matches!(message, PeerMessage::Disconnected | PeerMessage::TimedOut);
+        if local_sender.send(RoutedMessage { from, message }).is_err() || disconnected {
+            return;
+        }
+    }
+}
+/// Pings one client every `PING_INTERVAL` for as long as its connection lasts.
+fn ping_thread(slot: PlayerSlot, generation: u64, clients: Arc<Mutex<Clients>>) {
+    let index = slot as usize - 1;
+    loop {
+        sleep(PING_INTERVAL);
+        let mut clients = clients.lock().unwrap();
+        if clients.generations[index] != generation {
+            return;
+        }
+        let sent_at = clients.started.elapsed().as_micros() as u64;
+        // Anything older would have been answered by now if the connection were still up
+        let oldest = sent_at.saturating_sub(PEER_TIMEOUT.as_micros() as u64);
+        let pings = &mut clients.pings[index];
+        pings.retain(|&ping| ping >= oldest);
+        pings.push_back(sent_at);
+        let sender = match clients.senders[index].as_ref() {
+            Some(sender) => sender,
+            None => return,
+        };
+        let ping = Ping { sent_at };
+        let routed_message = RoutedMessage {
+            from: 0,
+            message: PeerMessage::Ping(ping),
+        };
+        if sender.send(routed_message).is_err() {
+            return;
+        }
+    }
+}
+/// Sends everything the host's session says to every connected client except the one it
+/// originally came from.
+fn broadcast_thread(message_receiver: Receiver<RoutedMessage>, clients: Arc<Mutex<Clients>>) {
+    for routed_message in message_receiver.iter() {
+        let clients = clients.lock().unwrap();
+        for (index, sender) in clients.senders.iter().enumerate() {
+            if index as PlayerSlot + 1 == routed_message.from {
+                continue;
+            }
+            if let Some(sender) = sender {
+                let _ = sender.send(routed_message.clone());
+            }
+        }
+    }
+}
+/// Lets in whoever connects once the match is underway, until `accept` fails with `BrokenPipe` or
+/// `NotConnected` to say nobody else can. Any other failure, whether accepting a connection or
+/// letting it join, is only reported to the host's session before waiting for the next one.
+fn accept_late_connections<F>(
+    my_name: String,
+    mut accept: F,
+    clients: Arc<Mutex<Clients>>,
+    local_sender: Sender<RoutedMessage>,
+    feed: Arc<Mutex<SpectatorFeed>>,
+) -> io::Result<()>
+where
+    F: FnMut() -> io::Result<Box<dyn Transport>>,
+{
+    loop {
+        let mut connection = match accept() {
+            Ok(connection) => connection,
+            Err(e) if matches!(e.kind(), ErrorKind::BrokenPipe | ErrorKind::NotConnected) => {
+                return Err(e)
+            }
+            Err(e) => {
+                notify(&local_sender, ConnectionNotice::JoinRefused(e.to_string()));
+                continue;
+            }
+        };
+        // Someone who connects and says nothing would otherwise keep everyone after them waiting
+        // Only this thread ever fills an open slot, so it's still open once they've shaken hands
+        let match_full = clients.lock().unwrap().open_slots.is_empty();
+        let joined = connection
+            .set_recv_timeout(Some(PEER_TIMEOUT))
+            .and_then(|_| {
+                exchange_handshakes(
+                    &my_name,
+                    &[],
+                    HOST_FEATURES,
+                    match_full,
+                    connection.as_mut(),
+                )
+            })
+            .and_then(|handshake| {
+                let name = handshake.my_name;
+                let refused = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", name, e));
+                let requested = |wanted: &str| {
+                    handshake
+                        .requested_features
+                        .iter()
+                        .any(|feature| feature == wanted)
+                };
+                if requested(SPECTATE_FEATURE) {
+                    // They never say anything, so hearing nothing is no reason to drop them
+                    connection.set_recv_timeout(None).map_err(refused)?;
+                    add_spectator(connection, &feed).map_err(refused)?;
+                    return Ok(Some(ConnectionNotice::SpectatorJoined(name.clone())));
+                }
+                let slot = if requested(REJOIN_FEATURE) {
+                    check_rejoin(connection.as_mut(), &clients).map_err(refused)?
+                } else {
+                    assign_open_slot(connection.as_mut(), &name, &clients).map_err(refused)?
+                };
+                // Either way, the session hears they're here from the connection itself and brings
+                // them up to date
+                connect_client(&clients, slot, connection, local_sender.clone(), true)
+                    .map_err(refused)?;
+                Ok(None)
+            });
+        let notice = match joined {
+            Ok(notice) => notice,
+            Err(e) => Some(ConnectionNotice::JoinRefused(e.to_string())),
+        };
+        if let Some(notice) = notice {
+            notify(&local_sender, notice);
+        }
+    }
+}
+/// Has the host's session report `notice`.
+fn notify(local_sender: &Sender<RoutedMessage>, notice: ConnectionNotice) {
+    // A session that's gone has no use for it
+    let _ = local_sender.send(RoutedMessage {
+        from: 0,
+        message: PeerMessage::Notice(notice),
+    });
+}
+/// Gives a player joining the match underway the lowest open slot, going through the same steps
+/// with them as with the players there from the start.
+fn assign_open_slot(
+    connection: &mut dyn Transport,
+    name: &str,
+    clients: &Arc<Mutex<Clients>>,
+) -> io::Result<PlayerSlot> {
+    let (input_delay, _) = host_measure_timing(connection)?;
+    let slot_assignment = {
+        let clients = clients.lock().unwrap();
+        let slot = *clients.open_slots.first().ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::PermissionDenied,
+                Rejection::MatchFull.to_string(),
+            )
+        })?;
+        let mut player_names = clients.player_names.clone();
+        player_names[slot as usize] = name.to_string();
+        SlotAssignment {
+            slot,
+            player_names,
+            rejoin_token: clients.rejoin_tokens[slot as usize - 1],
+            open_slots: clients.open_slots[1..].to_vec(),
+            match_underway: true,
+        }
+    };
+    connection.send_item(slot_assignment.clone())?;
+    // Both superseded once they're brought up to date, which they wait for instead of a countdown
+    connection.send_item(SetInputDelay { input_delay })?;
+    connection.send_item(StartCountdown { micros: 0 })?;
+    connection.flush()?;
+    let mut clients = clients.lock().unwrap();
+    clients.open_slots.remove(0);
+    clients.player_names = slot_assignment.player_names;
+    Ok(slot_assignment.slot)
+}
+/// Reads a rejoining client's `RejoinRequest` and tells them whether it's accepted, returning the
+/// slot they're back in if it is.
+fn check_rejoin(
+    connection: &mut dyn Transport,
+    clients: &Arc<Mutex<Clients>>,
+) -> io::Result<PlayerSlot> {
+    let rejoin_request: RejoinRequest = connection.receive_item()?;
+    let is_known = {
+        let clients = clients.lock().unwrap();
+        // Nobody has been given an open slot's token yet
+        !clients.open_slots.contains(&rejoin_request.slot)
+            && (rejoin_request.slot as usize)
+                .checked_sub(1)
+                .and_then(|index| clients.rejoin_tokens.get(index))
+                == Some(&rejoin_request.rejoin_token)
+    };
+    if !is_known {
+        connection.send_item(HandshakeReply::Rejected(Rejection::UnknownRejoin))?;
+        connection.flush()?;
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            Rejection::UnknownRejoin.to_string(),
+        ));
+    }
+    connection.send_item(HandshakeReply::Accepted)?;
+    connection.flush()?;
+    Ok(rejoin_request.slot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game::commands::Chat, network::memory::MemoryTransport};
+
+    /// Clients who have all been given the matching rejoin token, but none of whom is connected.
+    fn clients_with_tokens(rejoin_tokens: Vec<u64>) -> Arc<Mutex<Clients>> {
+        Arc::new(Mutex::new(Clients {
+            senders: vec![None; rejoin_tokens.len()],
+            generations: vec![0; rejoin_tokens.len()],
+            pings: vec![VecDeque::new(); rejoin_tokens.len()],
+            player_names: vec!["player".to_string(); rejoin_tokens.len() + 1],
+            rejoin_tokens,
+            open_slots: Vec::new(),
+            started: Instant::now(),
+        }))
+    }
+
+    /// Has the client in `slot` ask to rejoin with `rejoin_token`, returning what the host made of
+    /// it and what the client was told.
+    fn rejoin(slot: PlayerSlot, rejoin_token: u64) -> (io::Result<PlayerSlot>, HandshakeReply) {
+        spawn_network_thread(move || {
+            let clients = clients_with_tokens(vec![11, 22]);
+            let (host, client) = MemoryTransport::pair();
+            let (mut host, mut client): (Box<dyn Transport>, Box<dyn Transport>) =
+                (Box::new(host), Box::new(client));
+            client
+                .send_item(RejoinRequest { slot, rejoin_token })
+                .unwrap();
+            let result = check_rejoin(host.as_mut(), &clients);
+            (result, client.receive_item().unwrap())
+        })
+        .join()
+        .unwrap()
+    }
+
+    #[test]
+    fn keeps_accepting_after_a_failure() {
+        let clients = clients_with_tokens(vec![11]);
+        let mut failures = vec![
+            io::Error::new(ErrorKind::NotConnected, "Listener closed"),
+            io::Error::new(ErrorKind::ConnectionReset, "Reset before it was accepted"),
+        ];
+        let (local_sender, local_receiver) = mpsc::channel();
+        let (feed, _) = spectator_feed(vec!["host".to_string(), "client".to_string()]);
+        let result = accept_late_connections(
+            "host".to_string(),
+            || Err(failures.pop().unwrap()),
+            clients,
+            local_sender,
+            feed,
+        );
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::NotConnected);
+        let notices: Vec<_> = local_receiver.try_iter().collect();
+        assert!(matches!(
+            notices[..],
+            [RoutedMessage {
+                message: PeerMessage::Notice(ConnectionNotice::JoinRefused(_)),
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn gives_up_on_connections_that_say_nothing() {
+        let clients = clients_with_tokens(vec![11]);
+        let (silent, connection) = MemoryTransport::pair();
+        let mut connection: Option<Box<dyn Transport>> = Some(Box::new(connection));
+        let (local_sender, local_receiver) = mpsc::channel();
+        let (feed, _) = spectator_feed(vec!["host".to_string(), "client".to_string()]);
+        spawn_network_thread(move || {
+            accept_late_connections(
+                "host".to_string(),
+                || {
+                    connection
+                        .take()
+                        .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "No more"))
+                },
+                clients,
+                local_sender,
+                feed,
+            )
+        })
+        .join()
+        .unwrap()
+        .unwrap_err();
+        assert!(matches!(
+            local_receiver.try_recv(),
+            Ok(RoutedMessage {
+                message: PeerMessage::Notice(ConnectionNotice::JoinRefused(_)),
+                ..
+            })
+        ));
+        drop(silent);
+    }
+
+    #[test]
+    fn only_measures_pings_the_host_sent() {
+        let clients = clients_with_tokens(vec![11]);
+        clients.lock().unwrap().pings[0].push_back(1234);
+        let (local_sender, local_receiver) = mpsc::channel();
+        spawn_network_thread(move || {
+            let (host, client) = MemoryTransport::pair();
+            let mut client: Box<dyn Transport> = Box::new(client);
+            let input_thread = spawn_network_thread(move || {
+                client_input_thread(1, 0, Box::new(host), local_sender, clients)
+            });
+            for message in [
+                PeerMessage::RoundTrip(RoundTrip { micros: u64::MAX }),
+                PeerMessage::Reconnected,
+                PeerMessage::Pong(Ping { sent_at: 0 }),
+                PeerMessage::Pong(Ping { sent_at: 1234 }),
+                PeerMessage::Pong(Ping { sent_at: 1234 }),
+                PeerMessage::Chat(Chat {
+                    text: "hi".to_string(),
+                }),
+            ] {
+                client.send_item(message).unwrap();
+            }
+            client.flush().unwrap();
+            drop(client);
+            input_thread.join().unwrap();
+        })
+        .join()
+        .unwrap();
+        let messages: Vec<_> = local_receiver
+            .try_iter()
+            .map(|routed_message| routed_message.message)
+            .collect();
+        assert!(matches!(
+            messages[..],
+            [
+                PeerMessage::RoundTrip(RoundTrip { micros }),
+                PeerMessage::Chat(_),
+                PeerMessage::Disconnected,
+            ] if micros < PEER_TIMEOUT.as_micros() as u64
+        ));
+    }
+
+    #[test]
+    fn rejoins_with_the_right_token() {
+        let (result, reply) = rejoin(2, 22);
+        assert_eq!(result.unwrap(), 2);
+        assert!(matches!(reply, HandshakeReply::Accepted));
+    }
+
+    #[test]
+    fn refuses_someone_elses_token() {
+        let (result, reply) = rejoin(2, 11);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(matches!(
+            reply,
+            HandshakeReply::Rejected(Rejection::UnknownRejoin)
+        ));
+    }
+
+    #[test]
+    fn refuses_slots_nobody_is_in() {
+        for slot in [0, 3, PlayerSlot::MAX] {
+            let (result, reply) = rejoin(slot, 11);
+            assert!(result.is_err());
+            assert!(matches!(
+                reply,
+                HandshakeReply::Rejected(Rejection::UnknownRejoin)
+            ));
+        }
+    }
+}
@@ -0,0 +1,164 @@
+use std::{
+    io::{self, ErrorKind},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use super::{LatencyStats, Transport};
+
+/// One end of an in-process connection, for driving sessions without any sockets.
+pub struct MemoryTransport {
+    sender: Sender<Vec<u8>>,
+    receiver: Arc<Mutex<Receiver<Vec<u8>>>>,
+    recv_timeout: Option<Duration>,
+}
+impl MemoryTransport {
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (a_sender, b_receiver) = mpsc::channel();
+        let (b_sender, a_receiver) = mpsc::channel();
+        (
+            MemoryTransport {
+                sender: a_sender,
+                receiver: Arc::new(Mutex::new(a_receiver)),
+                recv_timeout: None,
+            },
+            MemoryTransport {
+                sender: b_sender,
+                receiver: Arc::new(Mutex::new(b_receiver)),
+                recv_timeout: None,
+            },
+        )
+    }
+}
+impl Transport for MemoryTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.sender
+            .send(message.to_vec())
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Other end was dropped"))
+    }
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        let receiver = self.receiver.lock().unwrap();
+        let result = match self.recv_timeout {
+            Some(timeout) => receiver.recv_timeout(timeout),
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        result.map_err(|e| match e {
+            RecvTimeoutError::Timeout => io::Error::new(ErrorKind::TimedOut, "Nothing arrived"),
+            RecvTimeoutError::Disconnected => {
+                io::Error::new(ErrorKind::UnexpectedEof, "Other end was dropped")
+            }
+        })
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(MemoryTransport {
+            sender: self.sender.clone(),
+            receiver: self.receiver.clone(),
+            recv_timeout: self.recv_timeout,
+        }))
+    }
+    fn set_recv_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.recv_timeout = timeout;
+        Ok(())
+    }
+    fn latency(&self) -> LatencyStats {
+        LatencyStats {
+            round_trip: Some(Duration::ZERO),
+            jitter: Some(Duration::ZERO),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        game::commands::{Chat, PeerMessage, TimingPacket},
+        network::spawn_network_thread,
+    };
+
+    #[test]
+    fn messages_arrive_whole_and_in_order() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        let messages = [
+            b"first".to_vec(),
+            Vec::new(),
+            vec![7u8; 100_000],
+            b"last".to_vec(),
+        ];
+        for message in messages.iter() {
+            a.send(message).unwrap();
+        }
+        for message in messages.iter() {
+            assert_eq!(b.recv().unwrap(), *message);
+        }
+    }
+
+    #[test]
+    fn each_direction_is_separate() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        a.send(b"to b").unwrap();
+        b.send(b"to a").unwrap();
+        assert_eq!(a.recv().unwrap(), b"to a");
+        assert_eq!(b.recv().unwrap(), b"to b");
+    }
+
+    #[test]
+    fn clones_share_the_connection() {
+        let (a, mut b) = MemoryTransport::pair();
+        let mut sending_clone = a.try_clone().unwrap();
+        let mut receiving_clone = a.try_clone().unwrap();
+        sending_clone.send(b"from a clone").unwrap();
+        b.send(b"to any clone").unwrap();
+        assert_eq!(b.recv().unwrap(), b"from a clone");
+        assert_eq!(receiving_clone.recv().unwrap(), b"to any clone");
+    }
+
+    #[test]
+    fn items_keep_their_type() {
+        // Encoding a `Message` takes more stack than a test thread has
+        spawn_network_thread(|| {
+            let (a, b) = MemoryTransport::pair();
+            let (mut a, mut b): (Box<dyn Transport>, Box<dyn Transport>) =
+                (Box::new(a), Box::new(b));
+            a.send_item(TimingPacket { sequence_number: 3 }).unwrap();
+            a.send_item(PeerMessage::Chat(Chat {
+                text: "hello".to_string(),
+            }))
+            .unwrap();
+            let timing_packet: TimingPacket = b.receive_item().unwrap();
+            assert_eq!(timing_packet.sequence_number, 3);
+            // A chat message where a timing packet was expected is an error, not a garbled packet
+            let error = b.receive_item::<TimingPacket>().unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn recv_times_out() {
+        let (_a, mut b) = MemoryTransport::pair();
+        b.set_recv_timeout(Some(Duration::from_millis(10))).unwrap();
+        assert_eq!(b.recv().unwrap_err().kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn hanging_up_ends_the_stream() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        a.send(b"goodbye").unwrap();
+        drop(a);
+        // Whatever was sent before hanging up still arrives
+        assert_eq!(b.recv().unwrap(), b"goodbye");
+        assert_eq!(b.recv().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(
+            b.send(b"anyone?").unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+    }
+}
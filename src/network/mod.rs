@@ -0,0 +1,508 @@
+use crate::game::{
+    checksum::simulation_hash,
+    commands::{Handshake, HandshakeReply, Message, Rejection, TimingPacket},
+};
+
+use super::*;
+use alkahest::{
+    deserialize, private::BareFormula, serialize_to_vec, Deserialize, Formula, SerializeRef,
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    fmt,
+    io::{self, ErrorKind, Read, Write},
+    sync::mpsc::{Receiver, RecvTimeoutError, Sender},
+};
+
+/// The biggest frame we'll read or write. Far more than any message needs, but little enough that
+/// a corrupt or hostile length can't make us allocate much.
+pub const MAX_FRAME_SIZE: usize = 1 << 20;
+
+/// Why a frame couldn't be read or written. Returned inside an `io::Error`, so callers that care
+/// can tell the cases apart with `FrameError::of`.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The stream ended partway through a frame of `len` bytes.
+    Truncated { len: usize },
+    /// The frame is `len` bytes long, more than `MAX_FRAME_SIZE`.
+    TooLarge { len: usize },
+    /// The frame's bytes aren't a valid item.
+    Malformed(String),
+}
+impl FrameError {
+    /// The `FrameError` behind `error`, if there is one.
+    pub fn of(error: &io::Error) -> Option<&FrameError> {
+        error.get_ref()?.downcast_ref()
+    }
+}
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Truncated { len } => {
+                write!(f, "stream ended partway through a {} byte frame", len)
+            }
+            FrameError::TooLarge { len } => write!(
+                f,
+                "{} byte frame is over the {} byte limit",
+                len, MAX_FRAME_SIZE
+            ),
+            FrameError::Malformed(reason) => write!(f, "malformed frame: {}", reason),
+        }
+    }
+}
+impl std::error::Error for FrameError {}
+impl From<FrameError> for io::Error {
+    fn from(error: FrameError) -> Self {
+        let kind = match error {
+            FrameError::Truncated { .. } => ErrorKind::UnexpectedEof,
+            FrameError::TooLarge { .. } | FrameError::Malformed(_) => ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+pub fn encode_item<ItemType: SerializeRef<ItemType> + Formula + BareFormula>(
+    item: &ItemType,
+) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    serialize_to_vec::<ItemType, _>(item, &mut buffer);
+    buffer
+}
+pub fn decode_item<ItemType: Formula + for<'a> Deserialize<'a, ItemType>>(
+    buffer: &[u8],
+) -> io::Result<ItemType> {
+    deserialize::<ItemType, ItemType>(buffer)
+        .map_err(|e| FrameError::Malformed(format!("{:?}", e)).into())
+}
+/// Writes `buffer` prefixed by its length, so it can be read back out of a stream.
+pub fn write_frame<W: Write>(out: &mut W, buffer: &[u8]) -> io::Result<()> {
+    // Sending something the other end would refuse to read would only fail later, and less clearly
+    if buffer.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge { len: buffer.len() }.into());
+    }
+    out.write_u32::<BigEndian>(buffer.len() as u32)?;
+    out.write_all(buffer)?;
+    Ok(())
+}
+/// Reads a frame written by `write_frame`, refusing any longer than `MAX_FRAME_SIZE` before
+/// allocating room for it.
+pub fn read_frame<R: Read>(in_stream: &mut R) -> io::Result<Vec<u8>> {
+    let len = in_stream.read_u32::<BigEndian>()? as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge { len }.into());
+    }
+    let mut buffer = vec![0u8; len];
+    in_stream.read_exact(&mut buffer).map_err(|e| {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            FrameError::Truncated { len }.into()
+        } else {
+            e
+        }
+    })?;
+    Ok(buffer)
+}
+pub fn serialize_item<W: Write, ItemType: SerializeRef<ItemType> + Formula + BareFormula>(
+    out: &mut W,
+    item: &ItemType,
+) -> io::Result<()> {
+    write_frame(out, &encode_item(item))
+}
+pub fn deserialize_item<R: Read, ItemType: Formula + for<'a> Deserialize<'a, ItemType>>(
+    in_stream: &mut R,
+) -> io::Result<ItemType> {
+    decode_item(&read_frame(in_stream)?)
+}
+
+pub mod client;
+pub mod host;
+pub mod memory;
+pub mod simulated;
+pub mod spectators;
+pub mod tcp;
+pub mod udp;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LatencyStats {
+    /// Smoothed round trip time, if the transport has been able to measure it.
+    pub round_trip: Option<Duration>,
+    /// Smoothed deviation of round trip samples from `round_trip`.
+    pub jitter: Option<Duration>,
+}
+
+/// A reliable, ordered channel of framed messages to one other peer.
+pub trait Transport: Send {
+    fn send(&mut self, message: &[u8]) -> io::Result<()>;
+    /// Blocks until the next message arrives.
+    fn recv(&mut self) -> io::Result<Vec<u8>>;
+    /// Pushes out anything `send` buffered.
+    fn flush(&mut self) -> io::Result<()>;
+    /// Another handle to the same connection, so one thread can send while another receives.
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>>;
+    /// Makes `recv` fail with `TimedOut` once nothing has arrived for `timeout`. Set on the handle
+    /// that receives; other handles to the same connection may or may not share it.
+    fn set_recv_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+    fn latency(&self) -> LatencyStats;
+}
+impl dyn Transport + '_ {
+    /// Sends `item` wrapped in a `Message`.
+    pub fn send_item<ItemType: Into<Message>>(&mut self, item: ItemType) -> io::Result<()> {
+        self.send(&encode_item::<Message>(&item.into()))
+    }
+    /// Receives the next message, which has to be an `ItemType`.
+    pub fn receive_item<ItemType: TryFrom<Message, Error = Message>>(
+        &mut self,
+    ) -> io::Result<ItemType> {
+        let message: Message = decode_item(&self.recv()?)?;
+        ItemType::try_from(message).map_err(|message| {
+            let expected = std::any::type_name::<ItemType>();
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Expected {} but got a {} message",
+                    expected.rsplit("::").next().unwrap_or(expected),
+                    message.name()
+                ),
+            )
+        })
+    }
+}
+
+/// Bumped whenever anything sent over the network changes layout or meaning.
+pub const PROTOCOL_VERSION: u32 = 6;
+/// Requested by a client reconnecting to a match it was playing in.
+pub const REJOIN_FEATURE: &str = "rejoin";
+/// Requested by someone connecting to watch a match.
+pub const SPECTATE_FEATURE: &str = "spectate";
+
+/// How long a connection can go without us sending anything before we send a heartbeat instead.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+/// How long a peer can go without us hearing anything from them, heartbeats included, before we
+/// give up on the connection.
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the host measures the round trip time to each client during a match.
+const PING_INTERVAL: Duration = Duration::from_millis(500);
+/// How long after the host sends out the countdown a match starts, which has to leave every player
+/// time to open their window.
+const START_COUNTDOWN: Duration = Duration::from_secs(1);
+/// Stack for every thread that sends messages. Unoptimized, alkahest inlines the serializer for
+/// every variant of a `Message` into one frame of a few MiB, more than a thread gets by default.
+const NETWORK_THREAD_STACK_SIZE: usize = 8 << 20;
+
+/// Spawns a thread with room on its stack to encode a `Message`, which any thread that might send
+/// one has to be.
+pub fn spawn_network_thread<F, T>(f: F) -> thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::Builder::new()
+        .stack_size(NETWORK_THREAD_STACK_SIZE)
+        .spawn(f)
+        .expect("Couldn't spawn network thread")
+}
+
+/// Enough input delay that commands sent over a link with this round trip time usually arrive
+/// before the frame they're for. Round trips longer than `PEER_TIMEOUT` count as that long, since
+/// the connection would have timed out before seeing one.
+pub fn input_delay_for(round_trip: Duration) -> u64 {
+    (round_trip.min(PEER_TIMEOUT).as_micros() / TICK_TIME.as_micros()) as u64 + 2
+}
+
+const TIMING_PACKET_COUNT: u64 = 10;
+/// Returns the input delay the connection calls for, along with its average round trip time.
+fn host_measure_timing(connection: &mut dyn Transport) -> io::Result<(u64, Duration)> {
+    let start_time = Instant::now();
+    let mut max_elapsed: Duration = Duration::from_micros(1);
+    let mut start_packet = start_time;
+    for i in 0..TIMING_PACKET_COUNT + 1 {
+        if i != 0 {
+            let timing_packet: TimingPacket = connection.receive_item()?;
+            let packet_elapsed = start_packet.elapsed();
+            max_elapsed = max_elapsed.max(packet_elapsed);
+            if timing_packet.sequence_number != i {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Got wrong sequence number in timing packets",
+                ));
+            }
+        }
+        if i != TIMING_PACKET_COUNT {
+            connection.send_item(TimingPacket {
+                sequence_number: i + 1,
+            })?;
+            connection.flush()?;
+            start_packet = Instant::now();
+        }
+    }
+    let average_elapsed = start_time.elapsed();
+    println!(
+        "Total elapsed time {} us, max {} us",
+        average_elapsed.as_micros(),
+        max_elapsed.as_micros()
+    );
+    if let Some(round_trip) = connection.latency().round_trip {
+        println!("Transport round trip time {} us", round_trip.as_micros());
+    }
+    Ok((
+        input_delay_for(max_elapsed),
+        average_elapsed / TIMING_PACKET_COUNT as u32,
+    ))
+}
+fn client_measure_timing(connection: &mut dyn Transport) -> io::Result<()> {
+    for _ in 0..TIMING_PACKET_COUNT {
+        let timing_packet: TimingPacket = connection
+            .receive_item()
+            .expect("Unable to receive timing packet");
+        connection
+            .send_item(timing_packet)
+            .expect("Unable to send timing packet");
+        connection.flush()?;
+    }
+    Ok(())
+}
+
+/// Swaps handshakes with the other end of a fresh connection, failing unless both sides accept the
+/// other's. We accept the same protocol version and simulation, and only features we support. Once
+/// the match is full we also only accept players rejoining it and spectators.
+fn exchange_handshakes(
+    my_name: &str,
+    requested_features: &[&str],
+    supported_features: &[&str],
+    match_full: bool,
+    connection: &mut dyn Transport,
+) -> io::Result<Handshake> {
+    connection.send_item(Handshake {
+        protocol_version: PROTOCOL_VERSION,
+        simulation_hash: simulation_hash(),
+        my_name: my_name.to_string(),
+        requested_features: requested_features
+            .iter()
+            .map(|feature| feature.to_string())
+            .collect(),
+    })?;
+    let handshake: Handshake = connection.receive_item().map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Couldn't read handshake, the other side may be running an incompatible version: {}",
+                e
+            ),
+        )
+    })?;
+    let unsupported_feature = handshake
+        .requested_features
+        .iter()
+        .find(|feature| !supported_features.contains(&feature.as_str()));
+    let is_rejoining_or_spectating = handshake
+        .requested_features
+        .iter()
+        .any(|feature| feature == REJOIN_FEATURE || feature == SPECTATE_FEATURE);
+    let reply = if handshake.protocol_version != PROTOCOL_VERSION {
+        HandshakeReply::Rejected(Rejection::ProtocolVersion(PROTOCOL_VERSION))
+    } else if handshake.simulation_hash != simulation_hash() {
+        HandshakeReply::Rejected(Rejection::SimulationHash(simulation_hash()))
+    } else if let Some(feature) = unsupported_feature {
+        HandshakeReply::Rejected(Rejection::UnsupportedFeature(feature.clone()))
+    } else if match_full && !is_rejoining_or_spectating {
+        HandshakeReply::Rejected(Rejection::MatchFull)
+    } else {
+        HandshakeReply::Accepted
+    };
+    connection.send_item(reply.clone())?;
+    connection.flush()?;
+    let their_reply: HandshakeReply = connection.receive_item()?;
+    if let HandshakeReply::Rejected(rejection) = reply {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Rejected {}: {}", handshake.my_name, rejection),
+        ));
+    }
+    if let HandshakeReply::Rejected(rejection) = their_reply {
+        return Err(io::Error::new(
+            ErrorKind::ConnectionRefused,
+            format!("Rejected by {}: {}", handshake.my_name, rejection),
+        ));
+    }
+    Ok(handshake)
+}
+fn output_thread<ItemType: Into<Message>>(
+    message_receiver: Receiver<ItemType>,
+    mut out_stream: Box<dyn Transport>,
+) -> io::Result<()> {
+    // Wait for something to send, then send everything else queued up behind it in one go
+    for message in message_receiver.iter() {
+        out_stream.send_item(message)?;
+        for message in message_receiver.try_iter() {
+            out_stream.send_item(message)?;
+        }
+        out_stream.flush()?;
+    }
+    Ok(())
+}
+/// Like `output_thread`, but sends `heartbeat` whenever there's been nothing else to send for
+/// `HEARTBEAT_INTERVAL`.
+fn heartbeat_output_thread<ItemType: Into<Message> + Clone>(
+    message_receiver: Receiver<ItemType>,
+    mut out_stream: Box<dyn Transport>,
+    heartbeat: ItemType,
+) -> io::Result<()> {
+    loop {
+        let message = match message_receiver.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => heartbeat.clone(),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        out_stream.send_item(message)?;
+        for message in message_receiver.try_iter() {
+            out_stream.send_item(message)?;
+        }
+        out_stream.flush()?;
+    }
+}
+fn input_thread<ItemType: TryFrom<Message, Error = Message>>(
+    message_sender: Sender<ItemType>,
+    mut in_stream: Box<dyn Transport>,
+) -> io::Result<()> {
+    loop {
+        let message = in_stream.receive_item::<ItemType>()?;
+        message_sender
+            .send(message)
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "Nobody is reading messages"))?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, panic};
+
+    use super::*;
+    use crate::game::{
+        commands::{AbilityId, Command, PeerMessage, TimedCommand},
+        fixed::Fixed,
+    };
+    use simulated::SimulationRng;
+
+    fn timed_command() -> TimedCommand {
+        TimedCommand {
+            time: 1234,
+            command: Command::AbilityCommand(
+                AbilityId(0),
+                Fixed::from_int(10),
+                Fixed::from_int(-20),
+            ),
+        }
+    }
+    fn frame_of(message: Message) -> Vec<u8> {
+        spawn_network_thread(move || {
+            let mut frame = Vec::new();
+            serialize_item(&mut frame, &message).unwrap();
+            frame
+        })
+        .join()
+        .unwrap()
+    }
+    /// Decodes `frame` as a `Message`, failing the test if that panics.
+    fn decode(frame: &[u8]) -> io::Result<Message> {
+        panic::catch_unwind(|| deserialize_item::<_, Message>(&mut Cursor::new(frame)))
+            .unwrap_or_else(|_| panic!("Decoding {:?} panicked", frame))
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let frame = frame_of(Message::Peer(PeerMessage::Command(timed_command())));
+        match decode(&frame).unwrap() {
+            Message::Peer(PeerMessage::Command(decoded)) => {
+                assert_eq!(decoded.time, 1234);
+                assert_eq!(decoded.command, timed_command().command);
+            }
+            other => panic!("Decoded a {} message", other.name()),
+        }
+        let handshake = Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            simulation_hash: simulation_hash(),
+            my_name: "name".to_string(),
+            requested_features: vec![SPECTATE_FEATURE.to_string()],
+        };
+        match decode(&frame_of(Message::Handshake(handshake))).unwrap() {
+            Message::Handshake(decoded) => {
+                assert_eq!(decoded.my_name, "name");
+                assert_eq!(decoded.requested_features, [SPECTATE_FEATURE]);
+            }
+            other => panic!("Decoded a {} message", other.name()),
+        }
+    }
+
+    #[test]
+    fn refuses_oversized_frames_before_reading_them() {
+        for len in [MAX_FRAME_SIZE as u32 + 1, u32::MAX] {
+            let error = decode(&len.to_be_bytes()).unwrap_err();
+            assert!(matches!(
+                FrameError::of(&error),
+                Some(FrameError::TooLarge { len: found }) if *found == len as usize
+            ));
+        }
+        let error = write_frame(&mut Vec::new(), &vec![0; MAX_FRAME_SIZE + 1]).unwrap_err();
+        assert!(matches!(
+            FrameError::of(&error),
+            Some(FrameError::TooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn reports_truncated_frames() {
+        let frame = frame_of(Message::Peer(PeerMessage::Command(timed_command())));
+        for cut in 4..frame.len() {
+            let error = decode(&frame[..cut]).unwrap_err();
+            assert!(matches!(
+                FrameError::of(&error),
+                Some(FrameError::Truncated { len }) if *len == frame.len() - 4
+            ));
+        }
+        // Too short to even say how long it is
+        for cut in 0..4 {
+            let error = decode(&frame[..cut]).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn random_frames_decode_or_are_malformed() {
+        let mut rng = SimulationRng::new(0);
+        let mut malformed = 0;
+        for _ in 0..10_000 {
+            let len = rng.next_u64() % 64;
+            let mut frame = (len as u32).to_be_bytes().to_vec();
+            frame.extend((0..len).map(|_| rng.next_u64() as u8));
+            if let Err(error) = decode(&frame) {
+                assert!(
+                    matches!(FrameError::of(&error), Some(FrameError::Malformed(_))),
+                    "{:?} failed with {}",
+                    frame,
+                    error
+                );
+                malformed += 1;
+            }
+        }
+        assert!(malformed > 0);
+    }
+
+    #[test]
+    fn corrupted_frames_decode_or_are_malformed() {
+        let frame = frame_of(Message::Peer(PeerMessage::Command(timed_command())));
+        let mut rng = SimulationRng::new(1);
+        for _ in 0..10_000 {
+            // Flip a few bits anywhere after the length
+            let mut corrupted = frame.clone();
+            for _ in 0..1 + rng.next_u64() % 4 {
+                let index = 4 + rng.next_u64() as usize % (corrupted.len() - 4);
+                corrupted[index] ^= 1 << (rng.next_u64() % 8);
+            }
+            if let Err(error) = decode(&corrupted) {
+                assert!(matches!(
+                    FrameError::of(&error),
+                    Some(FrameError::Malformed(_))
+                ));
+            }
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io::{self, ErrorKind},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::udp::{DatagramSocket, UdpTransport};
+
+/// How a simulated link mistreats the datagrams sent over it. Probabilities go from 0 to 1.
+#[derive(Clone, Debug, Default)]
+pub struct LinkConditions {
+    /// One way delay applied to every datagram.
+    pub latency: Duration,
+    /// Each datagram is delayed by up to this much on top of `latency`, chosen uniformly.
+    pub jitter: Duration,
+    pub loss: f64,
+    pub duplication: f64,
+    /// Chance a datagram is held back by an extra `latency + jitter`, so later ones overtake it.
+    pub reordering: f64,
+}
+
+/// SplitMix64. Tiny, and the same sequence on every platform for a given seed.
+pub struct SimulationRng {
+    state: u64,
+}
+impl SimulationRng {
+    pub fn new(seed: u64) -> Self {
+        SimulationRng { state: seed }
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+    pub fn chance(&mut self, probability: f64) -> bool {
+        self.next_f64() < probability
+    }
+}
+
+struct InFlight {
+    /// Datagrams ordered by delivery time, then by the order they were sent in.
+    queue: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    sent_count: u64,
+    rng: SimulationRng,
+}
+/// Everything travelling from one end of the link to the other.
+struct LinkDirection {
+    in_flight: Mutex<InFlight>,
+    arrived: Condvar,
+    conditions: LinkConditions,
+}
+impl LinkDirection {
+    fn new(conditions: LinkConditions, seed: u64) -> Self {
+        LinkDirection {
+            in_flight: Mutex::new(InFlight {
+                queue: BinaryHeap::new(),
+                sent_count: 0,
+                rng: SimulationRng::new(seed),
+            }),
+            arrived: Condvar::new(),
+            conditions,
+        }
+    }
+    fn send(&self, datagram: &[u8]) {
+        let conditions = &self.conditions;
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.rng.chance(conditions.loss) {
+            return;
+        }
+        let copies = if in_flight.rng.chance(conditions.duplication) {
+            2
+        } else {
+            1
+        };
+        let now = Instant::now();
+        for _ in 0..copies {
+            let mut delay =
+                conditions.latency + conditions.jitter.mul_f64(in_flight.rng.next_f64());
+            if in_flight.rng.chance(conditions.reordering) {
+                delay += conditions.latency + conditions.jitter;
+            }
+            let sequence = in_flight.sent_count;
+            in_flight.sent_count += 1;
+            in_flight
+                .queue
+                .push(Reverse((now + delay, sequence, datagram.to_vec())));
+        }
+        self.arrived.notify_all();
+    }
+    fn recv_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        let deadline = Instant::now() + timeout;
+        let mut in_flight = self.in_flight.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let next_delivery = in_flight
+                .queue
+                .peek()
+                .map(|Reverse((deliver_at, ..))| *deliver_at);
+            match next_delivery {
+                Some(deliver_at) if deliver_at <= now => {
+                    let Reverse((_, _, datagram)) = in_flight.queue.pop().unwrap();
+                    let len = datagram.len().min(buffer.len());
+                    buffer[..len].copy_from_slice(&datagram[..len]);
+                    return Ok(len);
+                }
+                _ if deadline <= now => {
+                    return Err(io::Error::new(ErrorKind::TimedOut, "No datagram arrived"));
+                }
+                next_delivery => {
+                    let wake_at = next_delivery.map_or(deadline, |at| at.min(deadline));
+                    in_flight = self
+                        .arrived
+                        .wait_timeout(in_flight, wake_at - now)
+                        .unwrap()
+                        .0;
+                }
+            }
+        }
+    }
+}
+
+/// One end of an in-process link that delays, drops, duplicates and reorders datagrams as set
+/// out in its `LinkConditions`. Random choices come from a seeded RNG, so the same seed makes the
+/// same choices for the same sequence of datagrams.
+pub struct SimulatedSocket {
+    outgoing: Arc<LinkDirection>,
+    incoming: Arc<LinkDirection>,
+}
+impl SimulatedSocket {
+    /// Both ends of a link with the same conditions in each direction.
+    pub fn pair(conditions: LinkConditions, seed: u64) -> (SimulatedSocket, SimulatedSocket) {
+        let a_to_b = Arc::new(LinkDirection::new(conditions.clone(), seed));
+        let b_to_a = Arc::new(LinkDirection::new(conditions, !seed));
+        (
+            SimulatedSocket {
+                outgoing: a_to_b.clone(),
+                incoming: b_to_a.clone(),
+            },
+            SimulatedSocket {
+                outgoing: b_to_a,
+                incoming: a_to_b,
+            },
+        )
+    }
+}
+impl DatagramSocket for SimulatedSocket {
+    fn send(&self, datagram: &[u8]) -> io::Result<usize> {
+        self.outgoing.send(datagram);
+        Ok(datagram.len())
+    }
+    fn recv_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.incoming.recv_timeout(buffer, timeout)
+    }
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(SimulatedSocket {
+            outgoing: self.outgoing.clone(),
+            incoming: self.incoming.clone(),
+        })
+    }
+}
+
+/// Two connected transports with the UDP reliability layer running over a simulated link.
+pub fn simulated_transports(
+    conditions: LinkConditions,
+    seed: u64,
+) -> (UdpTransport<SimulatedSocket>, UdpTransport<SimulatedSocket>) {
+    let (a, b) = SimulatedSocket::pair(conditions, seed);
+    (UdpTransport::new(a), UdpTransport::new(b))
+}
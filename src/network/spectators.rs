@@ -0,0 +1,133 @@
+use std::{
+    io,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use crate::game::commands::{ConfirmedFrame, Message, SpectatorStart};
+
+use super::{
+    exchange_handshakes, input_thread, output_thread, spawn_network_thread, Transport,
+    SPECTATE_FEATURE,
+};
+
+/// What a session streams to spectators.
+pub enum SpectatorUpdate {
+    /// A final frame's state, from `Game::save_state`. Spectators joining from now on start from
+    /// it, so nothing before it needs keeping.
+    Snapshot { frame: u64, state: Vec<u8> },
+    /// Every command for a frame after the latest snapshot.
+    Frame(ConfirmedFrame),
+}
+
+/// The latest snapshot and everything streamed since, so late spectators can catch up.
+pub struct SpectatorFeed {
+    player_names: Vec<String>,
+    snapshot: Option<(u64, Vec<u8>)>,
+    /// Every confirmed frame from the snapshot's on.
+    history: Vec<ConfirmedFrame>,
+    spectators: Vec<Sender<Message>>,
+    /// Spectators that arrived before the first snapshot, which start as soon as it does.
+    waiting: Vec<Box<dyn Transport>>,
+}
+impl SpectatorFeed {
+    /// Sends the snapshot and everything since to a spectator, then keeps it up to date.
+    fn start(&mut self, connection: Box<dyn Transport>) -> io::Result<()> {
+        let (frame, state) = match self.snapshot.as_ref() {
+            Some(snapshot) => snapshot.clone(),
+            None => {
+                self.waiting.push(connection);
+                return Ok(());
+            }
+        };
+        let mut in_stream = connection.try_clone()?;
+        // Spectators never say anything, but reading keeps the transport's acknowledgements flowing
+        spawn_network_thread(move || while in_stream.recv().is_ok() {});
+        let (spectator_sender, spectator_receiver) = mpsc::channel();
+        let _ = spectator_sender.send(Message::SpectatorStart(SpectatorStart {
+            player_names: self.player_names.clone(),
+            frame,
+            state,
+        }));
+        for confirmed_frame in self.history.iter() {
+            let _ = spectator_sender.send(Message::ConfirmedFrame(confirmed_frame.clone()));
+        }
+        self.spectators.push(spectator_sender);
+        spawn_network_thread(|| output_thread(spectator_receiver, connection));
+        Ok(())
+    }
+}
+
+/// Streams every update sent into the returned channel to each spectator added to the returned
+/// feed. Spectators get a separate connection each and never send anything, so they can't hold up
+/// the players.
+pub fn spectator_feed(
+    player_names: Vec<String>,
+) -> (Arc<Mutex<SpectatorFeed>>, Sender<SpectatorUpdate>) {
+    let feed = Arc::new(Mutex::new(SpectatorFeed {
+        player_names,
+        snapshot: None,
+        history: Vec::new(),
+        spectators: Vec::new(),
+        waiting: Vec::new(),
+    }));
+    let (update_sender, update_receiver) = mpsc::channel();
+    let broadcast_feed = feed.clone();
+    spawn_network_thread(move || broadcast_updates(update_receiver, broadcast_feed));
+    (feed, update_sender)
+}
+/// Starts streaming to a spectator that has already swapped handshakes over `connection`.
+pub fn add_spectator(
+    connection: Box<dyn Transport>,
+    feed: &Arc<Mutex<SpectatorFeed>>,
+) -> io::Result<()> {
+    feed.lock().unwrap().start(connection)
+}
+fn broadcast_updates(update_receiver: Receiver<SpectatorUpdate>, feed: Arc<Mutex<SpectatorFeed>>) {
+    for update in update_receiver.iter() {
+        let mut feed = feed.lock().unwrap();
+        match update {
+            SpectatorUpdate::Snapshot { frame, state } => {
+                feed.history
+                    .retain(|confirmed_frame| confirmed_frame.frame >= frame);
+                feed.snapshot = Some((frame, state));
+                for connection in std::mem::take(&mut feed.waiting) {
+                    // A spectator we can't start streaming to has nothing to lose
+                    let _ = feed.start(connection);
+                }
+            }
+            SpectatorUpdate::Frame(confirmed_frame) => {
+                // Spectators that have gone away are dropped
+                feed.spectators.retain(|spectator| {
+                    spectator
+                        .send(Message::ConfirmedFrame(confirmed_frame.clone()))
+                        .is_ok()
+                });
+                feed.history.push(confirmed_frame);
+            }
+        }
+    }
+}
+
+/// Joins the host at the other end of `connection` as a spectator.
+pub fn spectate(
+    my_name: String,
+    mut connection: Box<dyn Transport>,
+) -> (SpectatorStart, Receiver<ConfirmedFrame>) {
+    exchange_handshakes(
+        &my_name,
+        &[SPECTATE_FEATURE],
+        &[],
+        false,
+        connection.as_mut(),
+    )
+    .expect("Failed to exchange handshakes");
+    let spectator_start: SpectatorStart = connection
+        .receive_item()
+        .expect("Unable to read spectator start");
+    let (confirmed_sender, confirmed_receiver) = mpsc::channel();
+    spawn_network_thread(|| input_thread(confirmed_sender, connection));
+    (spectator_start, confirmed_receiver)
+}
@@ -0,0 +1,49 @@
+use std::{
+    io::{self, ErrorKind, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use super::{read_frame, write_frame, LatencyStats, Transport};
+
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+impl TcpTransport {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        Ok(TcpTransport { stream })
+    }
+}
+impl Transport for TcpTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        write_frame(&mut self.stream, message)
+    }
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        read_frame(&mut self.stream).map_err(|e| {
+            // Unix reports a read timeout as `WouldBlock`
+            if e.kind() == ErrorKind::WouldBlock {
+                io::Error::new(ErrorKind::TimedOut, e)
+            } else {
+                e
+            }
+        })
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpTransport {
+            stream: self.stream.try_clone()?,
+        }))
+    }
+    fn set_recv_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        // A frame cut off part way through leaves the stream unusable, but anyone using a timeout
+        // gives up on the connection when it expires anyway
+        self.stream.set_read_timeout(timeout)
+    }
+    fn latency(&self) -> LatencyStats {
+        // Acknowledgements and retransmits happen inside the kernel, so there's nothing to measure
+        LatencyStats::default()
+    }
+}
@@ -0,0 +1,501 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io::{self, Cursor, ErrorKind, Read},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{LatencyStats, Transport};
+
+/// How many of the most recent unacknowledged messages are repeated in every datagram, so a lost
+/// datagram is recovered by the next one instead of waiting for a retransmit.
+pub const REDUNDANT_MESSAGES: usize = 8;
+/// Datagrams are filled with redundant messages up to this size. A single larger message is still
+/// sent on its own.
+const MAX_DATAGRAM_SIZE: usize = 1200;
+/// How long a reader waits for a datagram before resending whatever hasn't been acknowledged.
+const RESEND_INTERVAL: Duration = Duration::from_millis(10);
+/// How far past the next expected message we hold on to messages that arrive early. Anything
+/// further ahead is dropped and recovered by a resend once the gap before it is filled.
+const RECEIVE_WINDOW: u32 = 1024;
+const HEADER_SIZE: usize = 5;
+const MESSAGE_HEADER_SIZE: usize = 8;
+
+struct UdpState {
+    next_sequence: u32,
+    /// Messages sent but not acknowledged yet, oldest first, with when they were first sent.
+    unacked: VecDeque<(u32, Vec<u8>, Instant)>,
+    /// Sequence number of the next message we expect, i.e. everything before it has arrived.
+    next_expected: u32,
+    /// Messages that arrived ahead of a gap, held until the gap is filled. Only ever holds messages
+    /// within `RECEIVE_WINDOW` of `next_expected`.
+    out_of_order: BTreeMap<u32, Vec<u8>>,
+    /// Messages received in order that haven't been read yet.
+    delivered: VecDeque<Vec<u8>>,
+    /// Whether we've received something the other side doesn't know we have.
+    ack_pending: bool,
+    latency: LatencyStats,
+}
+impl UdpState {
+    /// Folds in a round trip sample the same way TCP does (RFC 6298). Samples include however long
+    /// the other side held on to the acknowledgement, and any resends due to loss.
+    fn add_round_trip_sample(&mut self, sample: Duration) {
+        let (round_trip, jitter) = match (self.latency.round_trip, self.latency.jitter) {
+            (Some(round_trip), Some(jitter)) => {
+                let deviation = sample.abs_diff(round_trip);
+                ((round_trip * 7 + sample) / 8, (jitter * 3 + deviation) / 4)
+            }
+            _ => (sample, sample / 2),
+        };
+        self.latency = LatencyStats {
+            round_trip: Some(round_trip),
+            jitter: Some(jitter),
+        };
+    }
+}
+
+/// Whether sequence number `a` was sent before `b`, allowing for the numbers wrapping around.
+fn sent_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// Anything that can carry unreliable datagrams to a single other peer.
+pub trait DatagramSocket: Send + Sized + 'static {
+    fn send(&self, datagram: &[u8]) -> io::Result<usize>;
+    /// Waits at most `timeout` for a datagram, failing with `WouldBlock` or `TimedOut` if none
+    /// arrives.
+    fn recv_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize>;
+    fn try_clone(&self) -> io::Result<Self>;
+}
+impl DatagramSocket for UdpSocket {
+    fn send(&self, datagram: &[u8]) -> io::Result<usize> {
+        UdpSocket::send(self, datagram)
+    }
+    fn recv_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.set_read_timeout(Some(timeout))?;
+        self.recv(buffer)
+    }
+    fn try_clone(&self) -> io::Result<Self> {
+        UdpSocket::try_clone(self)
+    }
+}
+
+/// One peer's share of a socket bound by a `UdpListener`.
+pub struct UdpPeerSocket {
+    socket: Arc<UdpSocket>,
+    address: SocketAddr,
+    incoming: Arc<Mutex<Receiver<Vec<u8>>>>,
+}
+impl DatagramSocket for UdpPeerSocket {
+    fn send(&self, datagram: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(datagram, self.address)
+    }
+    fn recv_timeout(&self, buffer: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        match self.incoming.lock().unwrap().recv_timeout(timeout) {
+            Ok(datagram) => {
+                let len = datagram.len().min(buffer.len());
+                buffer[..len].copy_from_slice(&datagram[..len]);
+                Ok(len)
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                Err(io::Error::new(ErrorKind::TimedOut, "No datagram arrived"))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                ErrorKind::BrokenPipe,
+                "Listener socket stopped reading",
+            )),
+        }
+    }
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(UdpPeerSocket {
+            socket: self.socket.clone(),
+            address: self.address,
+            incoming: self.incoming.clone(),
+        })
+    }
+}
+
+/// Lets any number of peers talk to us through one bound UDP socket, handing out a transport for
+/// each new address that sends us something.
+pub struct UdpListener {
+    socket: Arc<UdpSocket>,
+    new_peers: Receiver<(SocketAddr, Receiver<Vec<u8>>, Vec<u8>)>,
+}
+impl UdpListener {
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(address)?);
+        let (new_peer_sender, new_peers) = mpsc::channel();
+        let reader = socket.clone();
+        thread::spawn(move || route_datagrams(reader, new_peer_sender));
+        Ok(UdpListener { socket, new_peers })
+    }
+    /// Waits for the first datagram from an address we haven't heard from before and talks only to
+    /// that address through the returned transport. Addresses whose first datagram isn't one of
+    /// ours, e.g. a port scanner's, are skipped, and count as new again next time they send one.
+    pub fn accept(&self) -> io::Result<UdpTransport<UdpPeerSocket>> {
+        loop {
+            let (address, incoming, first_datagram) = self
+                .new_peers
+                .recv()
+                .map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e))?;
+            let connection = UdpTransport::new(UdpPeerSocket {
+                socket: self.socket.clone(),
+                address,
+                incoming: Arc::new(Mutex::new(incoming)),
+            });
+            if connection.handle_datagram(&first_datagram).is_ok() {
+                return Ok(connection);
+            }
+        }
+    }
+}
+/// Reads every datagram arriving at a listener's socket and passes it on to the peer it came from.
+fn route_datagrams(
+    socket: Arc<UdpSocket>,
+    new_peers: Sender<(SocketAddr, Receiver<Vec<u8>>, Vec<u8>)>,
+) -> io::Result<()> {
+    let mut peers: HashMap<SocketAddr, Sender<Vec<u8>>> = HashMap::new();
+    let mut buffer = vec![0u8; u16::MAX as usize];
+    loop {
+        let (len, address) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            // Some platforms report an earlier send to a peer that went away here
+            Err(e)
+                if e.kind() == ErrorKind::ConnectionReset
+                    || e.kind() == ErrorKind::ConnectionRefused =>
+            {
+                continue
+            }
+            Err(e) => return Err(e),
+        };
+        let mut datagram = buffer[..len].to_vec();
+        if let Some(peer) = peers.get(&address) {
+            match peer.send(datagram) {
+                Ok(()) => continue,
+                // Every transport for that address is gone, so it's a stranger again
+                Err(mpsc::SendError(unsent)) => {
+                    peers.remove(&address);
+                    datagram = unsent;
+                }
+            }
+        }
+        let (peer, incoming) = mpsc::channel();
+        peers.insert(address, peer);
+        // Nobody is accepting any more, so strangers are ignored
+        let _ = new_peers.send((address, incoming, datagram));
+    }
+}
+
+/// Reliable, ordered message delivery over UDP, or anything else that behaves like it.
+///
+/// Every datagram carries an acknowledgement of everything received so far plus the last
+/// `REDUNDANT_MESSAGES` unacknowledged messages, newest first. The oldest unacknowledged message
+/// rides along straight after the newest, so one that lost every redundant copy still gets through
+/// eventually. Receivers deliver messages in sequence order, drop duplicates and drop anything more
+/// than `RECEIVE_WINDOW` ahead of what they're waiting for. Sequence numbers wrap around once they
+/// run out.
+///
+/// Datagram layout (big endian): `u32` ack, `u8` message count, then each message as a `u32`
+/// sequence number and `u32` length followed by its bytes.
+pub struct UdpTransport<S: DatagramSocket = UdpSocket> {
+    socket: S,
+    state: Arc<Mutex<UdpState>>,
+    recv_timeout: Option<Duration>,
+}
+
+impl UdpTransport<UdpSocket> {
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+        Ok(UdpTransport::new(socket))
+    }
+}
+impl<S: DatagramSocket> UdpTransport<S> {
+    pub fn new(socket: S) -> Self {
+        UdpTransport {
+            socket,
+            state: Arc::new(Mutex::new(UdpState {
+                next_sequence: 0,
+                unacked: VecDeque::new(),
+                next_expected: 0,
+                out_of_order: BTreeMap::new(),
+                delivered: VecDeque::new(),
+                ack_pending: false,
+                latency: LatencyStats::default(),
+            })),
+            recv_timeout: None,
+        }
+    }
+    fn recv_message(&mut self) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0u8; u16::MAX as usize];
+        let deadline = self.recv_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(message) = self.state.lock().unwrap().delivered.pop_front() {
+                return Ok(message);
+            }
+            // Without a connection there's nothing to notice the other side has gone but silence
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                return Err(io::Error::new(ErrorKind::TimedOut, "Nothing arrived"));
+            }
+            match self.socket.recv_timeout(&mut buffer, RESEND_INTERVAL) {
+                Ok(len) => {
+                    // A malformed datagram is just another lost packet, the redundancy in later
+                    // ones makes up for it
+                    let _ = self.handle_datagram(&buffer[..len]);
+                }
+                // Refused means the other side isn't listening yet, so keep resending until it is
+                Err(e)
+                    if e.kind() == ErrorKind::WouldBlock
+                        || e.kind() == ErrorKind::TimedOut
+                        || e.kind() == ErrorKind::ConnectionRefused =>
+                {
+                    let needs_resend = {
+                        let state = self.state.lock().unwrap();
+                        state.ack_pending || !state.unacked.is_empty()
+                    };
+                    if needs_resend {
+                        self.send_datagram()?;
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    fn send_datagram(&self) -> io::Result<()> {
+        let datagram = {
+            let mut state = self.state.lock().unwrap();
+            let unacked = &state.unacked;
+            // Newest first, so a new message is never crowded out of its own datagram, and is read
+            // before anything else if the datagram is cut short. The oldest comes straight after
+            // it, since the other side can't get past a gap until that one arrives.
+            let mut newest = unacked.iter().rev().take(REDUNDANT_MESSAGES);
+            let oldest = unacked
+                .front()
+                .filter(|_| unacked.len() > REDUNDANT_MESSAGES);
+            let candidates = newest.next().into_iter().chain(oldest).chain(newest);
+            let mut messages = Vec::new();
+            let mut size = HEADER_SIZE;
+            for (sequence, message, _) in candidates {
+                size += MESSAGE_HEADER_SIZE + message.len();
+                if !messages.is_empty() && size > MAX_DATAGRAM_SIZE {
+                    break;
+                }
+                messages.push((*sequence, message));
+            }
+            let mut datagram = Vec::with_capacity(size);
+            datagram.write_u32::<BigEndian>(state.next_expected)?;
+            datagram.write_u8(messages.len() as u8)?;
+            for (sequence, message) in messages {
+                datagram.write_u32::<BigEndian>(sequence)?;
+                datagram.write_u32::<BigEndian>(message.len() as u32)?;
+                datagram.extend_from_slice(message);
+            }
+            state.ack_pending = false;
+            datagram
+        };
+        match self.socket.send(&datagram) {
+            // The other side isn't up yet, it'll get this once we resend
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+    fn handle_datagram(&self, datagram: &[u8]) -> io::Result<()> {
+        let mut cursor = Cursor::new(datagram);
+        let ack = cursor.read_u32::<BigEndian>()?;
+        let count = cursor.read_u8()?;
+        let mut state = self.state.lock().unwrap();
+        let mut newest_acked_sent_at = None;
+        while matches!(state.unacked.front(), Some((sequence, ..)) if sent_before(*sequence, ack)) {
+            let (_, _, sent_at) = state.unacked.pop_front().unwrap();
+            newest_acked_sent_at = Some(sent_at);
+        }
+        if let Some(sent_at) = newest_acked_sent_at {
+            state.add_round_trip_sample(sent_at.elapsed());
+        }
+        for _ in 0..count {
+            let sequence = cursor.read_u32::<BigEndian>()?;
+            let len = cursor.read_u32::<BigEndian>()?;
+            if len as usize > datagram.len() {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    "Message length exceeds datagram",
+                ));
+            }
+            let mut message = vec![0u8; len as usize];
+            cursor.read_exact(&mut message)?;
+            if sequence.wrapping_sub(state.next_expected) < RECEIVE_WINDOW {
+                state.out_of_order.entry(sequence).or_insert(message);
+            }
+            state.ack_pending = true;
+        }
+        loop {
+            let next_expected = state.next_expected;
+            match state.out_of_order.remove(&next_expected) {
+                Some(message) => {
+                    state.delivered.push_back(message);
+                    state.next_expected = next_expected.wrapping_add(1);
+                }
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: DatagramSocket> Transport for UdpTransport<S> {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let sequence = state.next_sequence;
+            state.next_sequence = sequence.wrapping_add(1);
+            state
+                .unacked
+                .push_back((sequence, message.to_vec(), Instant::now()));
+        }
+        self.send_datagram()
+    }
+    /// Blocks until the next message in sequence arrives, resending unacknowledged messages while
+    /// waiting.
+    fn recv(&mut self) -> io::Result<Vec<u8>> {
+        self.recv_message()
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        // Every message goes out in its own datagram as soon as it's sent
+        Ok(())
+    }
+    fn try_clone(&self) -> io::Result<Box<dyn Transport>> {
+        Ok(Box::new(UdpTransport {
+            socket: self.socket.try_clone()?,
+            state: self.state.clone(),
+            recv_timeout: self.recv_timeout,
+        }))
+    }
+    fn set_recv_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.recv_timeout = timeout;
+        Ok(())
+    }
+    fn latency(&self) -> LatencyStats {
+        self.state.lock().unwrap().latency
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loses everything sent through it and never receives anything.
+    struct NullSocket;
+    impl DatagramSocket for NullSocket {
+        fn send(&self, datagram: &[u8]) -> io::Result<usize> {
+            Ok(datagram.len())
+        }
+        fn recv_timeout(&self, _buffer: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+            Err(io::Error::new(ErrorKind::TimedOut, "Nothing ever arrives"))
+        }
+        fn try_clone(&self) -> io::Result<Self> {
+            Ok(NullSocket)
+        }
+    }
+
+    fn datagram(ack: u32, messages: &[(u32, &[u8])]) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        datagram.write_u32::<BigEndian>(ack).unwrap();
+        datagram.write_u8(messages.len() as u8).unwrap();
+        for (sequence, message) in messages {
+            datagram.write_u32::<BigEndian>(*sequence).unwrap();
+            datagram
+                .write_u32::<BigEndian>(message.len() as u32)
+                .unwrap();
+            datagram.extend_from_slice(message);
+        }
+        datagram
+    }
+
+    fn delivered(transport: &UdpTransport<NullSocket>) -> Vec<Vec<u8>> {
+        transport
+            .state
+            .lock()
+            .unwrap()
+            .delivered
+            .drain(..)
+            .collect()
+    }
+
+    #[test]
+    fn sequence_numbers_compare_across_the_wrap() {
+        assert!(sent_before(1, 2));
+        assert!(!sent_before(2, 1));
+        assert!(!sent_before(5, 5));
+        assert!(sent_before(u32::MAX, 0));
+        assert!(!sent_before(0, u32::MAX));
+    }
+
+    #[test]
+    fn delivers_in_order_across_the_wrap() {
+        let transport = UdpTransport::new(NullSocket);
+        transport.state.lock().unwrap().next_expected = u32::MAX - 1;
+        transport
+            .handle_datagram(&datagram(0, &[(0, b"c"), (u32::MAX, b"b")]))
+            .unwrap();
+        assert!(delivered(&transport).is_empty());
+        transport
+            .handle_datagram(&datagram(0, &[(u32::MAX - 1, b"a"), (0, b"c")]))
+            .unwrap();
+        assert_eq!(delivered(&transport), [b"a", b"b", b"c"]);
+        assert_eq!(transport.state.lock().unwrap().next_expected, 1);
+    }
+
+    #[test]
+    fn drops_messages_beyond_the_receive_window() {
+        let transport = UdpTransport::new(NullSocket);
+        transport
+            .handle_datagram(&datagram(
+                0,
+                &[(RECEIVE_WINDOW - 1, b"held"), (RECEIVE_WINDOW, b"dropped")],
+            ))
+            .unwrap();
+        let state = transport.state.lock().unwrap();
+        assert_eq!(
+            state.out_of_order.keys().copied().collect::<Vec<_>>(),
+            [RECEIVE_WINDOW - 1]
+        );
+    }
+
+    #[test]
+    fn acknowledges_across_the_wrap() {
+        let mut transport = UdpTransport::new(NullSocket);
+        transport.state.lock().unwrap().next_sequence = u32::MAX;
+        transport.send(b"a").unwrap();
+        transport.send(b"b").unwrap();
+        transport.send(b"c").unwrap();
+        // Everything before 1 has arrived, i.e. u32::MAX and 0 but not 1
+        transport.handle_datagram(&datagram(1, &[])).unwrap();
+        let state = transport.state.lock().unwrap();
+        let unacked: Vec<u32> = state
+            .unacked
+            .iter()
+            .map(|(sequence, ..)| *sequence)
+            .collect();
+        assert_eq!(unacked, [1]);
+    }
+
+    #[test]
+    fn listener_skips_strangers_sending_garbage() {
+        let listener = UdpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.socket.local_addr().unwrap();
+        let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        stranger.send_to(b"hi!", address).unwrap();
+        let mut client = UdpTransport::connect(address).unwrap();
+        client.send(b"hello").unwrap();
+        client.flush().unwrap();
+        let mut connection = listener.accept().unwrap();
+        assert_eq!(connection.recv().unwrap(), b"hello");
+    }
+}
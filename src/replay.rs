@@ -0,0 +1,117 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    path::Path,
+};
+
+use alkahest::alkahest;
+
+use crate::{
+    game::{commands::Command, Game, GameObjectId, RollbackableGame},
+    network::{deserialize_item, serialize_item},
+    ROLLBACK_WINDOW,
+};
+
+const REPLAY_MAGIC: &[u8; 4] = b"RBRP";
+/// Bumped whenever the layout of anything in a replay file changes.
+pub const REPLAY_VERSION: u32 = 2;
+
+/// Everything needed to rebuild the match's starting state.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct ReplayHeader {
+    pub version: u32,
+    /// Names of all players, indexed by slot.
+    pub player_names: Vec<String>,
+    /// Slot of the player who recorded the replay.
+    pub recorded_by: u32,
+    pub input_delay: u64,
+    /// The first frame, from `Game::save_state`.
+    pub starting_state: Vec<u8>,
+}
+/// A command as it was scheduled, in the order the recording peer received it.
+#[derive(Clone, Debug)]
+#[alkahest(Formula, SerializeRef, Deserialize)]
+pub struct ReplayEntry {
+    pub time: u64,
+    pub player_id: GameObjectId,
+    pub command: Command,
+}
+
+/// Writes a replay file as a match goes on: the magic bytes, then the header, then each entry,
+/// every one of them length prefixed.
+pub struct ReplayRecorder {
+    out: BufWriter<File>,
+}
+impl ReplayRecorder {
+    pub fn create<P: AsRef<Path>>(path: P, header: &ReplayHeader) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(REPLAY_MAGIC)?;
+        serialize_item(&mut out, header)?;
+        Ok(ReplayRecorder { out })
+    }
+    pub fn record(&mut self, entry: &ReplayEntry) -> io::Result<()> {
+        serialize_item(&mut self.out, entry)
+    }
+}
+
+pub struct Replay {
+    pub header: ReplayHeader,
+    pub entries: Vec<ReplayEntry>,
+}
+impl Replay {
+    /// Reads a whole replay file. A file cut short, e.g. by a crash, gives every entry up to the
+    /// cut.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut in_stream = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        in_stream.read_exact(&mut magic)?;
+        if &magic != REPLAY_MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Not a replay file"));
+        }
+        let header: ReplayHeader = deserialize_item(&mut in_stream)?;
+        if header.version != REPLAY_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Replay is version {}, only version {} is supported",
+                    header.version, REPLAY_VERSION
+                ),
+            ));
+        }
+        let mut entries = Vec::new();
+        loop {
+            match deserialize_item(&mut in_stream) {
+                Ok(entry) => entries.push(entry),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Replay { header, entries })
+    }
+    /// The frame after the last one with a command in it.
+    pub fn end_time(&self) -> u64 {
+        self.entries
+            .iter()
+            .map(|entry| entry.time + 1)
+            .max()
+            .unwrap_or(0)
+    }
+    /// Sets up the starting state with every recorded command scheduled, ready to be stepped
+    /// through.
+    pub fn start(&self) -> io::Result<RollbackableGame> {
+        let starting_game = Game::load_state(&self.header.starting_state)?;
+        let mut game = RollbackableGame::new(starting_game, ROLLBACK_WINDOW);
+        for entry in self.entries.iter() {
+            game.add_command(entry.player_id, entry.command.clone(), entry.time)
+                .expect("Replay entries can always be scheduled from the start");
+        }
+        Ok(game)
+    }
+    /// Re-simulates the match up to `frame`.
+    pub fn simulate_to(&self, frame: u64) -> io::Result<Game> {
+        let mut game = self.start()?;
+        game.advance_to(frame);
+        Ok(game.current_frame().clone())
+    }
+}
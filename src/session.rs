@@ -0,0 +1,899 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    io::{self, ErrorKind},
+    sync::mpsc::{Receiver, Sender},
+    time::Duration,
+};
+
+use crate::{
+    game::{
+        characters::Minkle,
+        checksum::{DesyncDetected, DesyncDetector},
+        commands::{
+            ChangeInputDelay, Chat, Command, ConfirmedFrame, ConnectionNotice, FrameAck,
+            FrameAdvantage, InvalidCommand, PeerMessage, PlayerSlot, RejectedCommand, RejoinState,
+            RoutedMessage, ScheduledCommand, SlotAdvantage, SlotCommand, TimedCommand,
+        },
+        fixed::Fixed,
+        prediction::InputPredictor,
+        Game, GameObjectId, Player, RollbackableGame,
+    },
+    network::{input_delay_for, spectators::SpectatorUpdate},
+    replay::{ReplayEntry, ReplayRecorder},
+    MAX_PREDICTION, ROLLBACK_WINDOW, TICK_TIME,
+};
+
+/// How many of each client's most recent round trip times the host bases the input delay on.
+const ROUND_TRIP_SAMPLES: usize = 10;
+/// How many frames ahead the host schedules an input delay change, so it usually reaches everyone
+/// before it takes effect.
+const INPUT_DELAY_CHANGE_LEAD: u64 = 30;
+/// How often, in frames, we tell the players we keep time with how far ahead of them we are.
+const FRAME_ADVANTAGE_INTERVAL: u64 = 10;
+/// How many frames ahead of the others we can get before slowing down to let them catch up.
+const MAX_FRAME_ADVANTAGE: i64 = 1;
+/// How often, in frames, spectators are sent a snapshot to start from, so one joining late only
+/// needs the frames since.
+const SPECTATOR_SNAPSHOT_INTERVAL: u64 = 600;
+
+/// Builds the starting state every peer agrees on, returning it along with each slot's player.
+pub fn setup_game(player_count: usize) -> (Game, Vec<GameObjectId>) {
+    let mut starting_game = Game::new();
+    let player_ids: Vec<_> = (0..player_count)
+        .map(|slot| {
+            Player::new(
+                &mut starting_game,
+                Fixed::from_int(100 * (slot as i64 + 1)),
+                Fixed::from_int(100),
+            )
+        })
+        .collect();
+    Minkle::new(&mut starting_game, player_ids[0]);
+    (starting_game, player_ids)
+}
+
+/// Something that happened to a session which the game loop may want to react to, e.g. by pausing
+/// or telling the player.
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// A player is back after a disconnect or timeout. On a client, the host's slot means we've
+    /// rejoined.
+    PeerConnected(PlayerSlot),
+    /// A player's connection failed. On a client, the host's slot means we're waiting to rejoin.
+    PeerDisconnected(PlayerSlot),
+    /// Like `PeerDisconnected`, but nothing was heard from the player for too long.
+    PeerTimedOut(PlayerSlot),
+    /// Our commands are scheduled this many frames ahead from now on.
+    InputDelayChanged(u64),
+    /// Our state disagrees with this player's.
+    Desync(PlayerSlot, DesyncDetected),
+    /// Commands for `frame` or later turned out different from what was predicted after they were
+    /// simulated, so `frames` frames were simulated again.
+    RollbackPerformed { frame: u64, frames: u64 },
+    /// We've gone as far past this player's last input as we're allowed to predict, so time stands
+    /// still until more of it arrives.
+    Stalled(PlayerSlot),
+    /// Time is moving again after a stall.
+    Resumed,
+    /// A player said something.
+    Chat(PlayerSlot, String),
+    /// A player sent a command they couldn't have issued playing fairly, which was thrown away.
+    InvalidCommand(PlayerSlot, InvalidCommand),
+    /// The host threw away one of our commands for this frame, for the given reason, so we
+    /// stopped simulating it too.
+    CommandRejected(u64, String),
+    /// A message claimed to be from a slot nobody is playing in, so it was thrown away.
+    UnknownPlayer(PlayerSlot),
+    /// A player sent something only the host may send, which was thrown away.
+    HostOnlyMessage(PlayerSlot),
+    /// The host's state to rejoin with was unusable, so we're reconnecting to be sent another.
+    RejoinFailed(String),
+    /// Someone by this name started watching the match.
+    SpectatorJoined(String),
+    /// The host turned away someone who connected once the match had started.
+    JoinRefused(String),
+    /// We couldn't get back in touch with the host this time, but will keep trying.
+    ReconnectFailed(String),
+    /// Nothing more will be recorded to the replay.
+    ReplayStopped(String),
+}
+impl fmt::Display for SessionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionEvent::PeerConnected(slot) => write!(f, "Player {} connected", slot + 1),
+            SessionEvent::PeerDisconnected(slot) => write!(f, "Player {} disconnected", slot + 1),
+            SessionEvent::PeerTimedOut(slot) => write!(f, "Player {} timed out", slot + 1),
+            SessionEvent::InputDelayChanged(input_delay) => {
+                write!(f, "Input delay changed to {} frames", input_delay)
+            }
+            SessionEvent::Desync(slot, desync) => {
+                write!(f, "Against player {}: {}", slot + 1, desync)
+            }
+            SessionEvent::RollbackPerformed { frame, frames } => {
+                write!(f, "Rolled back {} frames to frame {}", frames, frame)
+            }
+            SessionEvent::Stalled(slot) => write!(f, "Waiting for player {}", slot + 1),
+            SessionEvent::Resumed => write!(f, "Stopped waiting"),
+            SessionEvent::Chat(slot, text) => write!(f, "Player {}: {}", slot + 1, text),
+            SessionEvent::InvalidCommand(slot, invalid) => {
+                write!(f, "Ignored a command from player {}: {}", slot + 1, invalid)
+            }
+            SessionEvent::CommandRejected(frame, reason) => write!(
+                f,
+                "The host threw away our command for frame {}: {}",
+                frame, reason
+            ),
+            SessionEvent::UnknownPlayer(slot) => write!(
+                f,
+                "Ignored a message from player {}, who isn't in this match",
+                slot + 1
+            ),
+            SessionEvent::HostOnlyMessage(slot) => write!(
+                f,
+                "Ignored a message from player {} that only the host may send",
+                slot + 1
+            ),
+            SessionEvent::RejoinFailed(reason) => write!(f, "Couldn't rejoin: {}", reason),
+            SessionEvent::SpectatorJoined(name) => write!(f, "{} is spectating", name),
+            SessionEvent::JoinRefused(reason) => write!(f, "Turned away a connection: {}", reason),
+            SessionEvent::ReconnectFailed(reason) => {
+                write!(f, "Couldn't reconnect to the host: {}", reason)
+            }
+            SessionEvent::ReplayStopped(reason) => {
+                write!(f, "Stopped recording replay: {}", reason)
+            }
+        }
+    }
+}
+
+/// One peer's side of a match, independent of any window or renderer: feed it local commands once
+/// per frame and it deals with the other peers, rollback and desync detection.
+pub struct Session {
+    game: RollbackableGame,
+    /// Each slot's player.
+    player_ids: Vec<GameObjectId>,
+    my_slot: PlayerSlot,
+    input_delay: u64,
+    /// A change of input delay the host has asked for that hasn't taken effect yet.
+    pending_input_delay: Option<ChangeInputDelay>,
+    /// The host's most recent round trip time measurements to each client, oldest first.
+    round_trips: BTreeMap<PlayerSlot, VecDeque<Duration>>,
+    /// The host relays whatever its session sends from other players, so these are tagged with
+    /// who they're from.
+    to_other_sender: Sender<RoutedMessage>,
+    from_other_receiver: Receiver<RoutedMessage>,
+    /// Our checksums are compared with each other connected player's separately.
+    desync_detectors: BTreeMap<PlayerSlot, DesyncDetector>,
+    /// Set once we've lost the host, or when joining late, until it sends us a `Rejoin`. Time
+    /// stands still meanwhile.
+    awaiting_rejoin: bool,
+    /// Our commands can't be scheduled before this frame, as we've already promised the others
+    /// we're done with every frame before it. After rejoining, they may also have confirmed frames
+    /// beyond our input delay.
+    next_command_frame: u64,
+    /// Commands issued while our input delay is catching up with `next_command_frame`, which go
+    /// out along with the next ones that can be scheduled.
+    held_commands: Vec<Command>,
+    /// The latest frame each player we keep time with has told us they're on. Clients keep time
+    /// with the host, and the host with every client.
+    remote_frames: BTreeMap<PlayerSlot, u64>,
+    /// How many frames ahead of us each of those players last said they were.
+    remote_advantages: BTreeMap<PlayerSlot, i64>,
+    /// Set while we're too far ahead and stretching ticks to let the others catch up.
+    slowing_down: bool,
+    /// How many frames past a player's last input we simulate before waiting for more of it.
+    max_prediction: u64,
+    /// The player we're waiting for, if we've got too far ahead of their input.
+    stalled_on: Option<PlayerSlot>,
+    spectator_feed: Option<SpectatorFeed>,
+    replay_recorder: Option<ReplayRecorder>,
+    event_sender: Option<Sender<SessionEvent>>,
+}
+
+/// Commands held back until they're confirmed, then sent on to spectators.
+struct SpectatorFeed {
+    sender: Sender<SpectatorUpdate>,
+    pending: BTreeMap<u64, Vec<SlotCommand>>,
+    /// Every frame before this one has been sent.
+    next_frame: u64,
+    /// The frame of the last snapshot sent, if there's been one.
+    snapshot_frame: Option<u64>,
+}
+
+impl Session {
+    pub fn new(
+        starting_game: Game,
+        player_ids: Vec<GameObjectId>,
+        my_slot: PlayerSlot,
+        input_delay: u64,
+        to_other_sender: Sender<RoutedMessage>,
+        from_other_receiver: Receiver<RoutedMessage>,
+    ) -> Self {
+        let mut game = RollbackableGame::new(starting_game, ROLLBACK_WINDOW);
+        let mut desync_detectors = BTreeMap::new();
+        for (slot, id) in player_ids.iter().enumerate() {
+            let slot = slot as PlayerSlot;
+            if slot != my_slot {
+                game.add_peer(*id);
+                desync_detectors.insert(slot, DesyncDetector::new());
+            }
+        }
+        Session {
+            game,
+            player_ids,
+            my_slot,
+            input_delay,
+            pending_input_delay: None,
+            round_trips: BTreeMap::new(),
+            to_other_sender,
+            from_other_receiver,
+            desync_detectors,
+            awaiting_rejoin: false,
+            next_command_frame: 0,
+            held_commands: Vec::new(),
+            remote_frames: BTreeMap::new(),
+            remote_advantages: BTreeMap::new(),
+            slowing_down: false,
+            max_prediction: MAX_PREDICTION,
+            stalled_on: None,
+            spectator_feed: None,
+            replay_recorder: None,
+            event_sender: None,
+        }
+    }
+    /// Records every command from now on into a replay.
+    pub fn record_to(&mut self, replay_recorder: ReplayRecorder) {
+        self.replay_recorder = Some(replay_recorder);
+    }
+    /// Sends every frame's commands to `sender` once they can no longer change, along with a
+    /// snapshot to start from now and then.
+    pub fn stream_to_spectators(&mut self, sender: Sender<SpectatorUpdate>) {
+        self.spectator_feed = Some(SpectatorFeed {
+            sender,
+            pending: BTreeMap::new(),
+            next_frame: self.game.confirmed_time(),
+            snapshot_frame: None,
+        });
+    }
+    /// Guesses the other players' commands with `predictor` until they arrive, rather than
+    /// assuming they issued none.
+    pub fn set_input_predictor(&mut self, predictor: Box<dyn InputPredictor>) {
+        self.game.set_predictor(predictor);
+    }
+    /// Waits for more input from a player once we've simulated `frames` frames past the last we
+    /// have, rather than predicting any further.
+    pub fn set_max_prediction(&mut self, frames: u64) {
+        // Nobody could take the first step without predicting at least one frame
+        assert!(frames > 0, "Have to predict at least one frame ahead");
+        assert!(
+            frames <= ROLLBACK_WINDOW,
+            "Can't predict further ahead than the rollback window"
+        );
+        self.max_prediction = frames;
+    }
+    /// Expects nothing from the players in `slots` until the host brings someone joining in them up
+    /// to date, as nobody has yet.
+    pub fn leave_open(&mut self, slots: &[PlayerSlot]) {
+        for slot in slots {
+            if let Some(player_id) = self.player_ids.get(*slot as usize) {
+                self.game.remove_peer(*player_id);
+                self.desync_detectors.remove(slot);
+            }
+        }
+    }
+    /// Plays nothing until the host brings us up to date, as the match started without us.
+    pub fn join_underway(&mut self) {
+        self.awaiting_rejoin = true;
+    }
+    /// Reports everything that happens from now on to `sender`. Without one, events are dropped.
+    pub fn send_events_to(&mut self, sender: Sender<SessionEvent>) {
+        self.event_sender = Some(sender);
+    }
+    pub fn game(&self) -> &RollbackableGame {
+        &self.game
+    }
+    /// Runs one frame: schedules and sends our commands, takes in whatever the other peers sent,
+    /// then steps the game forward. Nothing is scheduled or stepped while awaiting a rejoin, and
+    /// while stalled our commands are held back until time moves again.
+    pub fn tick(&mut self, commands: Vec<Command>) {
+        // The host would only throw away commands our character can't carry out
+        let my_id = self.player_ids[self.my_slot as usize];
+        let commands: Vec<Command> = commands
+            .into_iter()
+            .filter(|command| command.validate(self.game.current_frame(), my_id).is_ok())
+            .collect();
+        if self.awaiting_rejoin {
+            self.poll();
+            return;
+        }
+        let lagging_peer = self.lagging_peer();
+        if lagging_peer != self.stalled_on {
+            self.stalled_on = lagging_peer;
+            self.emit(match lagging_peer {
+                Some(slot) => SessionEvent::Stalled(slot),
+                None => SessionEvent::Resumed,
+            });
+        }
+        if self.stalled_on.is_some() {
+            self.held_commands.extend(commands);
+            self.poll();
+            return;
+        }
+        self.send_commands(commands);
+        self.poll();
+        self.game.step();
+        let frame_advantage = self.frame_advantage();
+        if frame_advantage > MAX_FRAME_ADVANTAGE {
+            self.slowing_down = true;
+        } else if frame_advantage <= 0 {
+            self.slowing_down = false;
+        }
+    }
+    /// A player whose input we'd have to predict more than `max_prediction` frames of to step
+    /// forward. Stalling for them also bounds how many frames the game holds on to.
+    fn lagging_peer(&self) -> Option<PlayerSlot> {
+        (0..self.player_ids.len() as PlayerSlot).find(|slot| {
+            match self.game.peer_frame(self.player_ids[*slot as usize]) {
+                Some(frame) => self.game.current_time() >= frame + self.max_prediction,
+                None => false,
+            }
+        })
+    }
+    /// How many frames ahead of the furthest behind player we keep time with we are, with the
+    /// time messages spend in flight cancelled out. Negative when we're the one behind.
+    pub fn frame_advantage(&self) -> i64 {
+        self.remote_frames
+            .iter()
+            .filter_map(|(slot, remote_frame)| {
+                let remote_advantage = self.remote_advantages.get(slot)?;
+                let local_advantage = self.game.current_time() as i64 - *remote_frame as i64;
+                // Each side sees the other as far behind as messages take to arrive, so half the
+                // difference is how far apart we really are
+                Some((local_advantage - remote_advantage) / 2)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+    /// How long the game loop should wait between ticks: a little longer than `TICK_TIME` while
+    /// we're ahead, so the others catch up without us ever stopping outright.
+    pub fn tick_time(&self) -> Duration {
+        if self.slowing_down {
+            TICK_TIME + TICK_TIME / 10
+        } else {
+            TICK_TIME
+        }
+    }
+    /// Says `text` to everyone else.
+    pub fn send_chat(&self, text: String) {
+        self.send(PeerMessage::Chat(Chat { text }));
+    }
+    /// Takes in everything the other peers have sent so far without advancing time, rolling back
+    /// at most once, to the earliest frame any of it was mispredicted for.
+    pub fn poll(&mut self) {
+        let messages: Vec<_> = self.from_other_receiver.try_iter().collect();
+        for RoutedMessage { from, message } in messages {
+            if self.awaiting_rejoin {
+                // Whatever we miss until then is part of the state the host rejoins us with
+                match message {
+                    PeerMessage::Rejoin(rejoin_state)
+                        if from == 0 && rejoin_state.slot == self.my_slot =>
+                    {
+                        self.try_rejoin(rejoin_state)
+                    }
+                    PeerMessage::Notice(notice) if from == 0 => self.report(notice),
+                    _ => {}
+                }
+                continue;
+            }
+            self.handle_message(from, message);
+        }
+        self.correct_mispredictions();
+        for (time, frame) in self.game.take_newly_confirmed() {
+            let mut dump_to_send = None;
+            let mut frame_checksum = None;
+            for desync_detector in self.desync_detectors.values_mut() {
+                let (checksum, dump) = desync_detector.add_local_frame(time, frame.clone());
+                frame_checksum = Some(checksum);
+                dump_to_send = dump_to_send.or(dump);
+            }
+            if let Some(frame_checksum) = frame_checksum {
+                self.send(PeerMessage::Checksum(frame_checksum));
+            }
+            if let Some(dump) = dump_to_send {
+                self.send(PeerMessage::StateDump(dump));
+            }
+        }
+        if let Some(feed) = self.spectator_feed.as_mut() {
+            let confirmed_time = self.game.confirmed_time();
+            while feed.next_frame < confirmed_time {
+                let commands = feed.pending.remove(&feed.next_frame).unwrap_or_default();
+                // Spectators going away doesn't matter to the match
+                let _ = feed.sender.send(SpectatorUpdate::Frame(ConfirmedFrame {
+                    frame: feed.next_frame,
+                    commands,
+                }));
+                feed.next_frame += 1;
+            }
+            let snapshot_due = match feed.snapshot_frame {
+                Some(frame) => feed.next_frame >= frame + SPECTATOR_SNAPSHOT_INTERVAL,
+                None => true,
+            };
+            // Every command before it has been sent, so the frame spectators are up to is final
+            if let Some(frame) = self.game.frame(feed.next_frame).filter(|_| snapshot_due) {
+                let _ = feed.sender.send(SpectatorUpdate::Snapshot {
+                    frame: feed.next_frame,
+                    state: frame.save_state(),
+                });
+                feed.snapshot_frame = Some(feed.next_frame);
+            }
+        }
+        let mut desyncs = Vec::new();
+        for (slot, desync_detector) in self.desync_detectors.iter_mut() {
+            for desync in desync_detector.take_detected() {
+                desyncs.push(SessionEvent::Desync(*slot, desync));
+            }
+        }
+        for desync in desyncs {
+            self.emit(desync);
+        }
+    }
+    fn handle_message(&mut self, from: PlayerSlot, message: PeerMessage) {
+        let their_id = match self.player_ids.get(from as usize) {
+            Some(id) => *id,
+            None => {
+                self.emit(SessionEvent::UnknownPlayer(from));
+                return;
+            }
+        };
+        // Otherwise any player could take over another's slot or the input delay, or make things
+        // up about connections
+        let is_host_only = matches!(
+            message,
+            PeerMessage::Rejoin(_)
+                | PeerMessage::ChangeInputDelay(_)
+                | PeerMessage::CommandRejected(_)
+                | PeerMessage::Notice(_)
+        );
+        if is_host_only && from != 0 {
+            self.emit(SessionEvent::HostOnlyMessage(from));
+            return;
+        }
+        // Only the host checks commands, on everyone's behalf. The others are each on a frame of
+        // their own, so they'd disagree over which ones were too late or too far ahead
+        if let PeerMessage::Command(timed_command) = &message {
+            if self.my_slot == 0 {
+                if let Err(invalid) = self.validate_command(their_id, timed_command) {
+                    self.send(PeerMessage::CommandRejected(RejectedCommand {
+                        slot: from,
+                        command: timed_command.clone(),
+                        reason: invalid.to_string(),
+                    }));
+                    self.emit(SessionEvent::InvalidCommand(from, invalid));
+                    return;
+                }
+            }
+        }
+        // The host passes on everything the other players say, in the order it takes them in
+        if self.my_slot == 0 && message.is_relayed() {
+            self.relay(from, message.clone());
+        }
+        match message {
+            PeerMessage::Command(timed_command) => {
+                let scheduled = self.game.add_remote_command(
+                    their_id,
+                    timed_command.command.clone(),
+                    timed_command.time,
+                );
+                match scheduled {
+                    Ok(()) => self.record_command(from, &timed_command.command, timed_command.time),
+                    Err(e) => self.emit(SessionEvent::InvalidCommand(
+                        from,
+                        InvalidCommand::TooLate(e),
+                    )),
+                }
+            }
+            PeerMessage::Ack(ack) => {
+                self.game.acknowledge(their_id, ack.frame);
+                if self.keeps_time_with(from) {
+                    self.remote_frames.insert(from, ack.current_frame);
+                }
+            }
+            PeerMessage::FrameAdvantage(frame_advantage) => {
+                let advantage = frame_advantage
+                    .advantages
+                    .iter()
+                    .find(|advantage| advantage.slot == self.my_slot);
+                if let Some(advantage) = advantage {
+                    self.remote_advantages.insert(from, advantage.frames);
+                }
+            }
+            PeerMessage::Checksum(frame_checksum) => {
+                let dump = self
+                    .desync_detectors
+                    .get_mut(&from)
+                    .and_then(|desync_detector| {
+                        desync_detector.add_remote_checksum(frame_checksum)
+                    });
+                if let Some(dump) = dump {
+                    self.send(PeerMessage::StateDump(dump));
+                }
+            }
+            PeerMessage::StateDump(dump) => {
+                if let Some(desync_detector) = self.desync_detectors.get_mut(&from) {
+                    desync_detector.add_remote_state(dump);
+                }
+            }
+            PeerMessage::Disconnected | PeerMessage::TimedOut => {
+                // Only our own network threads speak for the host, when we've lost it
+                if from == 0 {
+                    self.awaiting_rejoin = true;
+                } else {
+                    self.game.remove_peer(their_id);
+                    self.desync_detectors.remove(&from);
+                    self.round_trips.remove(&from);
+                    self.remote_frames.remove(&from);
+                    self.remote_advantages.remove(&from);
+                }
+                self.emit(if matches!(message, PeerMessage::TimedOut) {
+                    SessionEvent::PeerTimedOut(from)
+                } else {
+                    SessionEvent::PeerDisconnected(from)
+                });
+            }
+            PeerMessage::Reconnected => self.bring_up_to_date(from),
+            PeerMessage::Rejoin(rejoin_state) => {
+                if rejoin_state.slot == self.my_slot {
+                    self.try_rejoin(rejoin_state);
+                } else if let Some(rejoining_id) = self.player_ids.get(rejoin_state.slot as usize) {
+                    self.game
+                        .add_peer_from(*rejoining_id, rejoin_state.first_command_frame);
+                    self.desync_detectors
+                        .insert(rejoin_state.slot, DesyncDetector::new());
+                    self.emit(SessionEvent::PeerConnected(rejoin_state.slot));
+                } else {
+                    self.emit(SessionEvent::UnknownPlayer(rejoin_state.slot));
+                }
+            }
+            PeerMessage::RoundTrip(round_trip) => {
+                let round_trips = self.round_trips.entry(from).or_default();
+                round_trips.push_back(Duration::from_micros(round_trip.micros));
+                if round_trips.len() > ROUND_TRIP_SAMPLES {
+                    round_trips.pop_front();
+                }
+                self.adjust_input_delay();
+            }
+            PeerMessage::ChangeInputDelay(change) => self.pending_input_delay = Some(change),
+            PeerMessage::Chat(chat) => self.emit(SessionEvent::Chat(from, chat.text)),
+            PeerMessage::CommandRejected(rejected) => {
+                if rejected.slot == self.my_slot {
+                    self.take_back_command(rejected);
+                }
+            }
+            PeerMessage::Notice(notice) => self.report(notice),
+            PeerMessage::Heartbeat | PeerMessage::Ping(_) | PeerMessage::Pong(_) => {}
+        }
+    }
+    /// Simulates again whatever the game rolled back after getting a player's commands wrong.
+    fn correct_mispredictions(&mut self) {
+        let frame = self.game.current_time();
+        let frames = self.game.advance_to(self.game.latest_time());
+        if frames > 0 {
+            self.emit(SessionEvent::RollbackPerformed { frame, frames });
+        }
+    }
+    /// Schedules a change of input delay for everyone if the worst recent round trip time calls
+    /// for one. Only the host does this.
+    fn adjust_input_delay(&mut self) {
+        if self.pending_input_delay.is_some() {
+            return;
+        }
+        let worst_round_trip = match self.round_trips.values().flatten().max() {
+            Some(round_trip) => *round_trip,
+            None => return,
+        };
+        let input_delay = input_delay_for(worst_round_trip);
+        // Lowering it by a single frame isn't worth it, and would flip back and forth on a link
+        // that's right on the edge
+        if input_delay > self.input_delay || input_delay + 1 < self.input_delay {
+            let change = ChangeInputDelay {
+                frame: self.game.latest_time() + INPUT_DELAY_CHANGE_LEAD,
+                input_delay,
+            };
+            self.pending_input_delay = Some(change.clone());
+            self.send(PeerMessage::ChangeInputDelay(change));
+        }
+    }
+    /// Sends a player who has just reconnected to the host everything they need to carry on, and
+    /// tells everyone else to expect their commands again.
+    fn bring_up_to_date(&mut self, slot: PlayerSlot) {
+        // The state they get has to have been simulated with everything we've received
+        self.correct_mispredictions();
+        self.emit(SessionEvent::PeerConnected(slot));
+        let player_id = self.player_ids[slot as usize];
+        let frame = self.game.confirmed_time().max(self.game.oldest_time());
+        let state = self
+            .game
+            .frame(frame)
+            .expect("Confirmed frame was pruned")
+            .save_state();
+        let commands = self
+            .game
+            .commands_from(frame)
+            .into_iter()
+            .map(|(time, issuer_id, command)| ScheduledCommand {
+                time,
+                slot: self.slot_of(issuer_id),
+                command,
+            })
+            .collect();
+        // Nobody can have confirmed past our latest ack, but if they never noticed the disconnect,
+        // they may already hold the rejoining player to a later frame
+        let first_command_frame = self
+            .next_command_frame
+            .max(self.game.peer_frame(player_id).unwrap_or(0));
+        let connected = (0..self.player_ids.len() as PlayerSlot)
+            .filter(|other| {
+                *other != slot
+                    && (*other == self.my_slot
+                        || self
+                            .game
+                            .peer_frame(self.player_ids[*other as usize])
+                            .is_some())
+            })
+            .collect();
+        self.game.add_peer_from(player_id, first_command_frame);
+        self.desync_detectors.insert(slot, DesyncDetector::new());
+        self.send(PeerMessage::Rejoin(RejoinState {
+            slot,
+            frame,
+            state,
+            commands,
+            current_time: self.game.current_time(),
+            first_command_frame,
+            connected,
+            input_delay: self
+                .pending_input_delay
+                .as_ref()
+                .map_or(self.input_delay, |change| change.input_delay),
+        }));
+    }
+    /// Rejoins with `rejoin_state`, or if it's unusable, drops the connection to the host so we
+    /// reconnect and get sent another.
+    fn try_rejoin(&mut self, rejoin_state: RejoinState) {
+        if let Err(e) = self.rejoin(rejoin_state) {
+            self.emit(SessionEvent::RejoinFailed(e.to_string()));
+            self.send(PeerMessage::Disconnected);
+        }
+    }
+    /// Replaces our game with the host's, picking up where it is now. Until this succeeds we're
+    /// still waiting to rejoin, so a failure partway leaves nothing in use.
+    fn rejoin(&mut self, rejoin_state: RejoinState) -> io::Result<()> {
+        let player_id_of = |slot: PlayerSlot| {
+            self.player_ids.get(slot as usize).copied().ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Player {} isn't in this match", slot + 1),
+                )
+            })
+        };
+        let connected = rejoin_state
+            .connected
+            .iter()
+            .map(|slot| Ok((*slot, player_id_of(*slot)?)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let commands = rejoin_state
+            .commands
+            .into_iter()
+            .map(|scheduled| Ok((player_id_of(scheduled.slot)?, scheduled)))
+            .collect::<io::Result<Vec<_>>>()?;
+        let starting_game = Game::load_state(&rejoin_state.state)?;
+        self.game.reset_to(starting_game, rejoin_state.frame);
+        self.desync_detectors.clear();
+        self.remote_frames.clear();
+        self.remote_advantages.clear();
+        for (slot, player_id) in connected {
+            self.game.add_peer_from(player_id, rejoin_state.frame);
+            self.desync_detectors.insert(slot, DesyncDetector::new());
+        }
+        for (player_id, scheduled_command) in commands {
+            self.game
+                .add_command(player_id, scheduled_command.command, scheduled_command.time)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        }
+        self.game.advance_to(rejoin_state.current_time);
+        self.next_command_frame = rejoin_state.first_command_frame;
+        self.held_commands.clear();
+        self.input_delay = rejoin_state.input_delay;
+        self.pending_input_delay = None;
+        self.awaiting_rejoin = false;
+        self.emit(SessionEvent::PeerConnected(0));
+        if self.replay_recorder.take().is_some() {
+            self.emit(SessionEvent::ReplayStopped(
+                "commands were missed while disconnected".to_string(),
+            ));
+        }
+        Ok(())
+    }
+    /// Unschedules one of our commands the host threw away, as nobody else ever saw it.
+    fn take_back_command(&mut self, rejected: RejectedCommand) {
+        let my_id = self.player_ids[self.my_slot as usize];
+        let time = rejected.command.time;
+        let reason = match self
+            .game
+            .remove_command(my_id, &rejected.command.command, time)
+        {
+            Ok(()) => rejected.reason,
+            Err(e) => format!("{}, and it can't be taken back: {}", rejected.reason, e),
+        };
+        self.emit(SessionEvent::CommandRejected(time, reason));
+        if self.replay_recorder.take().is_some() {
+            self.emit(SessionEvent::ReplayStopped(
+                "a recorded command was thrown away by the host".to_string(),
+            ));
+        }
+    }
+    /// Checks a command from a client before it's relayed, recorded or scheduled. The client is
+    /// told if it's thrown away, so they can take it back.
+    fn validate_command(
+        &self,
+        player_id: GameObjectId,
+        timed_command: &TimedCommand,
+    ) -> Result<(), InvalidCommand> {
+        timed_command
+            .command
+            .validate(self.game.current_frame(), player_id)?;
+        self.game
+            .check_command_time(player_id, timed_command.time)
+            .map_err(InvalidCommand::TooLate)?;
+        // A player can get up to their max prediction, at most the rollback window, past our
+        // latest command, then schedule theirs an input delay later still
+        let input_delay = match self.pending_input_delay.as_ref() {
+            Some(change) => self.input_delay.max(change.input_delay),
+            None => self.input_delay,
+        };
+        let limit = self.game.latest_time() + 2 * input_delay + ROLLBACK_WINDOW;
+        if timed_command.time > limit {
+            return Err(InvalidCommand::TooFarAhead {
+                time: timed_command.time,
+                limit,
+            });
+        }
+        Ok(())
+    }
+    fn slot_of(&self, player_id: GameObjectId) -> PlayerSlot {
+        self.player_ids
+            .iter()
+            .position(|id| *id == player_id)
+            .expect("Command from something other than a player") as PlayerSlot
+    }
+    fn send_commands(&mut self, commands: Vec<Command>) {
+        if let Some(change) = self.pending_input_delay.as_ref() {
+            if self.game.current_time() >= change.frame {
+                self.input_delay = change.input_delay;
+                self.pending_input_delay = None;
+                self.emit(SessionEvent::InputDelayChanged(self.input_delay));
+            }
+        }
+        self.held_commands.extend(commands);
+        let time = self.game.current_time() + self.input_delay;
+        // After the input delay drops, frames we've already promised the others are done with come
+        // round again, so commands wait for the first frame we're still free to use
+        if time >= self.next_command_frame {
+            for command in std::mem::take(&mut self.held_commands) {
+                let timed_command = TimedCommand {
+                    time,
+                    command: command.clone(),
+                };
+                self.send(PeerMessage::Command(timed_command));
+                self.record_command(self.my_slot, &command, time);
+                self.game
+                    .add_command(self.player_ids[self.my_slot as usize], command, time)
+                    .expect("Couldn't schedule own command");
+            }
+            self.next_command_frame = time + 1;
+        }
+        // Sent even while holding commands back, as the others keep time by it
+        self.send(PeerMessage::Ack(FrameAck {
+            frame: self.next_command_frame,
+            current_frame: self.game.current_time(),
+        }));
+        if self
+            .game
+            .current_time()
+            .is_multiple_of(FRAME_ADVANTAGE_INTERVAL)
+            && !self.remote_frames.is_empty()
+        {
+            let advantages = self
+                .remote_frames
+                .iter()
+                .map(|(slot, remote_frame)| SlotAdvantage {
+                    slot: *slot,
+                    frames: self.game.current_time() as i64 - *remote_frame as i64,
+                })
+                .collect();
+            self.send(PeerMessage::FrameAdvantage(FrameAdvantage { advantages }));
+        }
+    }
+    /// Clients only keep time with the host, and the host with every client.
+    fn keeps_time_with(&self, slot: PlayerSlot) -> bool {
+        self.my_slot == 0 || slot == 0
+    }
+    /// Passes a newly scheduled command on to the replay and spectators, if there are any.
+    fn record_command(&mut self, slot: PlayerSlot, command: &Command, time: u64) {
+        if let Some(replay_recorder) = self.replay_recorder.as_mut() {
+            let entry = ReplayEntry {
+                time,
+                player_id: self.player_ids[slot as usize],
+                command: command.clone(),
+            };
+            // Losing the replay is no reason to stop playing
+            if let Err(e) = replay_recorder.record(&entry) {
+                self.replay_recorder = None;
+                self.emit(SessionEvent::ReplayStopped(e.to_string()));
+            }
+        }
+        if let Some(feed) = self.spectator_feed.as_mut() {
+            feed.pending.entry(time).or_default().push(SlotCommand {
+                slot,
+                command: command.clone(),
+            });
+        }
+    }
+    fn report(&self, notice: ConnectionNotice) {
+        self.emit(match notice {
+            ConnectionNotice::SpectatorJoined(name) => SessionEvent::SpectatorJoined(name),
+            ConnectionNotice::JoinRefused(reason) => SessionEvent::JoinRefused(reason),
+            ConnectionNotice::ReconnectFailed(reason) => SessionEvent::ReconnectFailed(reason),
+        });
+    }
+    fn emit(&self, event: SessionEvent) {
+        if let Some(event_sender) = self.event_sender.as_ref() {
+            // Nobody listening any more is the same as nobody listening in the first place
+            let _ = event_sender.send(event);
+        }
+    }
+    fn send(&self, message: PeerMessage) {
+        self.relay(self.my_slot, message);
+    }
+    fn relay(&self, from: PlayerSlot, message: PeerMessage) {
+        self.to_other_sender
+            .send(RoutedMessage { from, message })
+            .expect("Couldn't send message to other players");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::*;
+
+    #[test]
+    fn a_command_the_host_throws_away_is_taken_back() {
+        let (starting_game, player_ids) = setup_game(2);
+        let (to_client, from_host) = mpsc::channel();
+        let (to_host, from_client) = mpsc::channel();
+        let mut host = Session::new(
+            starting_game.clone(),
+            player_ids.clone(),
+            0,
+            1,
+            to_client,
+            from_client,
+        );
+        // Schedules commands further ahead than the host will believe anyone could be
+        let mut client = Session::new(starting_game, player_ids, 1, 100, to_host, from_host);
+        let (event_sender, event_receiver) = mpsc::channel();
+        client.send_events_to(event_sender);
+        client.tick(vec![Command::MoveByCommand(Fixed::ZERO, Fixed::ZERO)]);
+        assert_eq!(client.game().commands_from(0).len(), 1);
+        host.tick(Vec::new());
+        client.tick(Vec::new());
+        assert!(host.game().commands_from(0).is_empty());
+        assert!(client.game().commands_from(0).is_empty());
+        assert!(event_receiver
+            .try_iter()
+            .any(|event| matches!(event, SessionEvent::CommandRejected(100, _))));
+    }
+}
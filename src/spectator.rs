@@ -0,0 +1,85 @@
+use std::{
+    io::{self, ErrorKind},
+    sync::mpsc::Receiver,
+};
+
+use crate::{
+    game::{commands::ConfirmedFrame, Game, GameObjectId, RollbackableGame},
+    ROLLBACK_WINDOW,
+};
+
+/// How many frames a spectator stays behind the newest confirmed frame it has, so a late packet
+/// doesn't make it stutter.
+const SPECTATOR_DELAY: u64 = 30;
+
+/// Watches a match from confirmed commands alone. Nothing it sees is ever rolled back, and it
+/// never sends anything to the players.
+pub struct Spectator {
+    game: RollbackableGame,
+    player_ids: Vec<GameObjectId>,
+    confirmed_receiver: Receiver<ConfirmedFrame>,
+    /// Every frame before this one has had all of its commands received.
+    received_until: u64,
+}
+
+impl Spectator {
+    /// Starts watching from `starting_game` as frame `time`, with confirmed frames from then on
+    /// arriving through `confirmed_receiver`.
+    pub fn new(
+        starting_game: Game,
+        time: u64,
+        player_ids: Vec<GameObjectId>,
+        confirmed_receiver: Receiver<ConfirmedFrame>,
+    ) -> Self {
+        Spectator {
+            game: RollbackableGame::new_at(starting_game, time, ROLLBACK_WINDOW),
+            player_ids,
+            confirmed_receiver,
+            received_until: time,
+        }
+    }
+    pub fn game(&self) -> &RollbackableGame {
+        &self.game
+    }
+    /// Every frame before this one has had all of its commands received.
+    pub fn received_until(&self) -> u64 {
+        self.received_until
+    }
+    /// Simulates everything received so far, e.g. once the match is over.
+    pub fn catch_up(&mut self) {
+        self.game.advance_to(self.received_until);
+    }
+    /// Takes in newly confirmed frames and moves forward by a frame if far enough behind them,
+    /// skipping ahead if it's fallen well behind, e.g. after joining a match late. Fails if the
+    /// host sends something no match could contain, after which nothing more can be trusted.
+    pub fn tick(&mut self) -> io::Result<()> {
+        for confirmed_frame in self.confirmed_receiver.try_iter() {
+            for slot_command in confirmed_frame.commands {
+                let player_id =
+                    *self
+                        .player_ids
+                        .get(slot_command.slot as usize)
+                        .ok_or_else(|| {
+                            io::Error::new(
+                                ErrorKind::InvalidData,
+                                format!(
+                                    "Command from player {}, who isn't in this match",
+                                    slot_command.slot + 1
+                                ),
+                            )
+                        })?;
+                self.game
+                    .add_command(player_id, slot_command.command, confirmed_frame.frame)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            }
+            self.received_until = confirmed_frame.frame + 1;
+        }
+        if self.game.current_time() + SPECTATOR_DELAY < self.received_until {
+            self.game.step();
+        }
+        while self.game.current_time() + 2 * SPECTATOR_DELAY < self.received_until {
+            self.game.step();
+        }
+        Ok(())
+    }
+}